@@ -1,18 +1,49 @@
-use std::{io::Write, path::PathBuf};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use food_generator2::{
-    decode_mode, encode_mode,
-    file::{read_lib_from_file, save_lib_to_file},
-    syntax::compile,
+    decode_library, encode_library,
+    file::{read_library_from_file, save_library_to_file, Library},
+    fingerprint::{fingerprint, identify, WrongLibrary},
+    generator::GeneratorTable,
+    syntax::{compile_with_notes, export_tracery, lint, CompileOptions, LintOptions},
 };
 
+use scaffold::{scaffold, ScaffoldOptions};
+
+mod scaffold;
+
 #[derive(clap::Parser)]
 pub enum Cli {
     Compile {
         source_dir: PathBuf,
         save_file: PathBuf,
+        /// Name saved in the library, the name of the source directory by default.
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        #[arg(long)]
+        author: Option<String>,
+        /// Give up when the decoding table of a section grows past this many nodes.
+        #[arg(long)]
+        max_table_nodes: Option<usize>,
+        /// Give up when compiling takes longer than this many seconds.
+        #[arg(long)]
+        max_time: Option<u64>,
+        /// Start every text with the fingerprint of the library, so that texts of
+        /// another library are told apart when decoding.
+        #[arg(long)]
+        fingerprint: bool,
+        /// Check that `save_file` is what the sources compile to instead of writing it,
+        /// failing if it is out of date.
+        #[arg(long)]
+        check: bool,
     },
     Encode {
         lib: PathBuf,
@@ -21,6 +52,37 @@ pub enum Cli {
     Decode {
         lib: PathBuf,
         text: String,
+        /// Libraries to look for the one that wrote the text, if `lib` did not.
+        #[arg(long)]
+        other: Vec<PathBuf>,
+    },
+    /// Show the format version and metadata of a library file.
+    Info {
+        lib: PathBuf,
+    },
+    ExportTracery {
+        lib: PathBuf,
+        save_file: PathBuf,
+    },
+    /// Report what compiles but is probably not meant, like sections never used.
+    Lint {
+        source_dir: PathBuf,
+        #[arg(long, default_value_t = 40)]
+        max_rule_len: usize,
+        #[arg(long, default_value_t = 1000)]
+        max_imbalance: u64,
+    },
+    /// Write a library for the sentence template, with words taken from the corpus.
+    Scaffold {
+        corpus: PathBuf,
+        template: String,
+        out_dir: PathBuf,
+        #[arg(long, default_value_t = 64)]
+        words: usize,
+        #[arg(long, default_value_t = 2)]
+        min_len: usize,
+        #[arg(long, default_value_t = 4)]
+        max_len: usize,
     },
 }
 
@@ -31,10 +93,44 @@ fn main() -> Result<()> {
         Cli::Compile {
             source_dir,
             save_file,
+            name,
+            description,
+            author,
+            max_table_nodes,
+            max_time,
+            fingerprint,
+            check,
         } => {
+            let mut options = CompileOptions::default();
+            if let Some(max) = max_table_nodes {
+                options.limits.max_table_nodes = *max;
+            }
+            options.limits.max_time = max_time.map(Duration::from_secs);
+
+            if *check {
+                let compiled = compile_dir(source_dir, &options)?;
+                let saved = read_library_from_file(save_file)?;
+                if saved.content_hash() != compiled.content_hash() {
+                    return Err(anyhow!(
+                        "`{}`和源文件编译出来的不一样，需要重新编译",
+                        save_file.display()
+                    ));
+                }
+                println!("`{}`是最新的", save_file.display());
+                return Ok(());
+            }
+
             print!("正在编译...");
             flush()?;
-            let result = save_lib_to_file(&compile(source_dir)?, save_file);
+            let mut lib = compile_dir(source_dir, &options)?;
+            lib.meta.name = name.clone().or_else(|| {
+                let dir = source_dir.canonicalize().ok()?;
+                Some(dir.file_name()?.to_string_lossy().into_owned())
+            });
+            lib.meta.description = description.clone();
+            lib.meta.author = author.clone();
+            lib.meta.fingerprint = *fingerprint;
+            let result = save_library_to_file(&lib, save_file);
             match result {
                 Ok(_) => println!("完成"),
                 Err(_) => {
@@ -44,18 +140,115 @@ fn main() -> Result<()> {
             result?;
             return Ok(());
         }
-        Cli::Encode { lib, text } | Cli::Decode { lib, text } => (lib, text),
+        Cli::Info { lib } => {
+            let lib = read_library_from_file(lib)?;
+            let meta = &lib.meta;
+            println!("格式版本：{}", lib.version);
+            let texts = [
+                ("名称", &meta.name),
+                ("简介", &meta.description),
+                ("作者", &meta.author),
+                ("生成程序", &meta.created_by),
+            ];
+            for (label, text) in texts {
+                if let Some(text) = text {
+                    println!("{label}：{text}");
+                }
+            }
+            if let Some(hash) = meta.source_hash {
+                println!("源文件哈希：{hash:016x}");
+            }
+            // 第1版的文件没有记下这些
+            if lib.version >= 2 {
+                println!("压缩：{}", if meta.compression { "是" } else { "否" });
+                if meta.fingerprint {
+                    println!("指纹：{:04x}", fingerprint(&lib.map));
+                }
+            }
+            println!("内容哈希：{:016x}", lib.content_hash());
+            println!("段落数：{}", lib.map.len());
+            for name in &meta.section_names {
+                println!("  {name}");
+            }
+            return Ok(());
+        }
+        Cli::ExportTracery { lib, save_file } => {
+            let mut notes = Vec::new();
+            std::fs::write(save_file, export_tracery(&load_lib(lib)?.map, &mut notes)?)?;
+            for note in &notes {
+                eprintln!("注意：{note}");
+            }
+            return Ok(());
+        }
+        Cli::Lint {
+            source_dir,
+            max_rule_len,
+            max_imbalance,
+        } => {
+            let options = LintOptions {
+                max_rule_len: *max_rule_len,
+                max_imbalance: *max_imbalance,
+                ..LintOptions::default()
+            };
+            let warnings = lint(source_dir, &options)?;
+            for warning in &warnings {
+                println!("警告：{warning}\n");
+            }
+            println!("共{}个警告", warnings.len());
+            return Ok(());
+        }
+        Cli::Scaffold {
+            corpus,
+            template,
+            out_dir,
+            words,
+            min_len,
+            max_len,
+        } => {
+            let options = ScaffoldOptions {
+                words: *words,
+                min_len: *min_len,
+                max_len: *max_len,
+            };
+            scaffold(corpus, template, out_dir, &options)?;
+            compile_dir(out_dir, &CompileOptions::default())?;
+            println!("已生成{}", out_dir.join("entry.txt").display());
+            return Ok(());
+        }
+        Cli::Encode { lib, text } | Cli::Decode { lib, text, .. } => (lib, text),
     };
 
-    let lib = if lib_path.is_dir() {
-        compile(lib_path)?
-    } else {
-        read_lib_from_file(lib_path)?
-    };
+    let lib = load_lib(lib_path)?;
 
     let output = match cli {
-        Cli::Encode { .. } => encode_mode(&lib, text)?,
-        Cli::Decode { .. } => decode_mode(&lib, text)?,
+        Cli::Encode { .. } => encode_library(&lib, text)?,
+        Cli::Decode { ref other, .. } => {
+            let decoded = decode_library(&lib, text);
+            // 带指纹的库只有指纹对不上才是用错了库，
+            // 不带指纹的库读别的库的文本不一定出错，要看别的库认不认
+            let suspect = !lib.meta.fingerprint
+                || decoded.as_ref().is_err_and(|err| err.is::<WrongLibrary>());
+            if suspect && !other.is_empty() {
+                let others = other
+                    .iter()
+                    .map(|path| load_lib(path))
+                    .collect::<Result<Vec<_>>>()?;
+                let maps = others.iter().map(|other| &other.map);
+                if let Some(i) = identify(maps, &GeneratorTable::new(), text) {
+                    return Err(anyhow!(
+                        "这段文本是库`{}`（指纹{:04x}）生成的，不是`{}`",
+                        others[i]
+                            .meta
+                            .name
+                            .clone()
+                            .unwrap_or_else(|| other[i].display().to_string()),
+                        fingerprint(&others[i].map),
+                        lib_path.display()
+                    ));
+                }
+            }
+            decoded?
+        }
         _ => unreachable!(),
     };
 
@@ -64,6 +257,22 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn load_lib(path: &Path) -> Result<Library> {
+    if path.is_dir() {
+        compile_dir(path, &CompileOptions::default())
+    } else {
+        read_library_from_file(path)
+    }
+}
+
+fn compile_dir(dir: &Path, options: &CompileOptions) -> Result<Library> {
+    let compiled = compile_with_notes(dir, options)?;
+    for note in &compiled.notes {
+        eprintln!("注意：{note}");
+    }
+    Ok(Library::new(compiled.map, compiled.meta))
+}
+
 fn flush() -> std::io::Result<()> {
     std::io::stdout().flush()
 }
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, fmt::Write, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+
+pub struct ScaffoldOptions {
+    /// 每个小节最多的词数
+    pub words: usize,
+    pub min_len: usize,
+    pub max_len: usize,
+}
+
+/// Build `entry.txt` in `out_dir` from a sentence template such as `{名词}在{地点}{动词}`,
+/// filling every referenced section with frequent words of the corpus.
+pub fn scaffold(
+    corpus: &Path,
+    template: &str,
+    out_dir: &Path,
+    options: &ScaffoldOptions,
+) -> Result<()> {
+    if options.min_len == 0 || options.min_len > options.max_len {
+        return Err(anyhow!(
+            "invalid word length range {}..={}",
+            options.min_len,
+            options.max_len
+        ));
+    }
+
+    let names = template_sections(template);
+    if names.is_empty() {
+        return Err(anyhow!("template `{template}` references no section"));
+    }
+
+    let corpus = fs::read_to_string(corpus)
+        .map_err(|err| anyhow!("cannot read corpus `{}`: {err}", corpus.display()))?;
+    let candidates = candidates(&corpus, options.min_len, options.max_len);
+
+    // 按频率轮流分给各个小节，使每个小节的词都不互为前缀
+    let mut lists: Vec<Vec<&str>> = vec![Vec::new(); names.len()];
+    let mut next = 0;
+    for word in candidates {
+        if lists.iter().all(|list| list.len() >= options.words) {
+            break;
+        }
+        for offset in 0..lists.len() {
+            let list = &mut lists[(next + offset) % names.len()];
+            if list.len() < options.words && is_prefix_free(list, word) {
+                list.push(word);
+                next = (next + offset + 1) % names.len();
+                break;
+            }
+        }
+    }
+
+    let mut output = format!("[entry]\n{template}\n");
+    for (name, list) in names.iter().zip(&lists) {
+        if list.is_empty() {
+            return Err(anyhow!(
+                "no word in the corpus can be used for section `{name}`"
+            ));
+        }
+        write!(output, "\n[{name}]\n")?;
+        for word in list {
+            writeln!(output, "{word}")?;
+        }
+    }
+
+    fs::create_dir_all(out_dir)?;
+    let entry = out_dir.join("entry.txt");
+    if entry.exists() {
+        return Err(anyhow!("`{}` already exists", entry.display()));
+    }
+    fs::write(entry, output)?;
+    Ok(())
+}
+
+/// Sections referenced by the template, in order of first appearance.
+fn template_sections(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some((_, after)) = rest.split_once('{') {
+        let Some((inner, after)) = after.split_once('}') else {
+            break;
+        };
+        rest = after;
+
+        // `{$x}`是回溯，`{名词:x}`要去掉捕获名
+        let name = inner.split(':').next().unwrap_or_default().trim();
+        if !name.is_empty() && !name.starts_with('$') && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Words of the corpus within the length range, most frequent first.
+/// Runs of CJK characters have no word boundary, every substring of them counts.
+fn candidates(corpus: &str, min_len: usize, max_len: usize) -> Vec<&str> {
+    let mut count: HashMap<&str, usize> = HashMap::new();
+    for run in corpus
+        .split(|ch: char| !ch.is_alphanumeric())
+        .filter(|run| !run.is_empty())
+    {
+        let starts: Vec<usize> = run.char_indices().map(|(i, _)| i).collect();
+        let len = starts.len();
+        if !run.chars().any(is_cjk) {
+            if (min_len..=max_len).contains(&len) {
+                *count.entry(run).or_default() += 1;
+            }
+            continue;
+        }
+
+        for (i, &start) in starts.iter().enumerate() {
+            for n in min_len..=max_len.min(len - i) {
+                let end = starts.get(i + n).copied().unwrap_or(run.len());
+                *count.entry(&run[start..end]).or_default() += 1;
+            }
+        }
+    }
+
+    let mut words: Vec<(&str, usize)> = count.into_iter().collect();
+    // 频率相同时优先长词，再按字典序保证结果稳定
+    words.sort_by(|(a, ca), (b, cb)| {
+        cb.cmp(ca)
+            .then_with(|| b.chars().count().cmp(&a.chars().count()))
+            .then_with(|| a.cmp(b))
+    });
+    words.into_iter().map(|(word, _)| word).collect()
+}
+
+fn is_cjk(ch: char) -> bool {
+    matches!(ch,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{20000}'..='\u{2ffff}')
+}
+
+/// Whether `word` can join the list without one word being a prefix of another,
+/// which the decoder cannot tell apart.
+fn is_prefix_free(list: &[&str], word: &str) -> bool {
+    list.iter()
+        .all(|other| !other.starts_with(word) && !word.starts_with(other))
+}
+
+#[test]
+fn test_candidates() {
+    let corpus = "苹果很甜，苹果派也甜。apple pie, apple!";
+    let words = candidates(corpus, 2, 5);
+    assert_eq!(&words[..2], ["apple", "苹果"]);
+
+    let mut list = Vec::new();
+    for word in words {
+        if is_prefix_free(&list, word) {
+            list.push(word);
+        }
+    }
+    assert!(list.contains(&"苹果") && !list.contains(&"苹果派"));
+    assert_eq!(template_sections("{a}和{b:x}还有{$x}{a}"), ["a", "b"]);
+}
+
+#[test]
+fn test_scaffold() {
+    let dir = std::env::temp_dir().join(format!("fg2-scaffold-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let corpus = dir.join("corpus.txt");
+    fs::write(
+        &corpus,
+        "teapot tea cup teapot, tea! teapot cup teapot tea milk bb a longword",
+    )
+    .unwrap();
+    let options = ScaffoldOptions {
+        words: 2,
+        min_len: 2,
+        max_len: 6,
+    };
+    let run = |template: &str, out: &str, options: &ScaffoldOptions| {
+        scaffold(&corpus, template, &dir.join(out), options)
+            .map(|()| fs::read_to_string(dir.join(out).join("entry.txt")).unwrap())
+    };
+
+    // 按频率轮流分给各个小节，太短和太长的词不要，每个小节最多两个词
+    let entry = run("{物}和{杯:x}{$x}", "两节", &options).unwrap();
+    assert_eq!(
+        entry,
+        "[entry]\n{物}和{杯:x}{$x}\n\n[物]\nteapot\ncup\n\n[杯]\ntea\nmilk\n"
+    );
+    let map = food_generator2::syntax::compile(dir.join("两节")).unwrap();
+    let text = food_generator2::encode(&map, b"fg2").unwrap();
+    assert_eq!(food_generator2::decode(&map, &text).unwrap(), b"fg2");
+
+    // 同一小节里是别的词前缀的词被跳过
+    let options = ScaffoldOptions {
+        words: 3,
+        ..options
+    };
+    let entry = run("{物}了", "一节", &options).unwrap();
+    assert_eq!(entry, "[entry]\n{物}了\n\n[物]\nteapot\ncup\nmilk\n");
+    assert!(food_generator2::syntax::compile(dir.join("一节")).is_ok());
+
+    let err = run("{物}了", "一节", &options).unwrap_err();
+    assert!(err.to_string().contains("already exists"), "{err}");
+    let err = run("没有小节", "空", &options).unwrap_err();
+    assert!(err.to_string().contains("references no section"), "{err}");
+    let err = run(
+        "{物}了",
+        "太长",
+        &ScaffoldOptions {
+            min_len: 9,
+            max_len: 12,
+            ..options
+        },
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("no word in the corpus can be used for section `物`"),
+        "{err}"
+    );
+    let err = run(
+        "{物}了",
+        "反了",
+        &ScaffoldOptions {
+            min_len: 3,
+            max_len: 2,
+            ..options
+        },
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("invalid word length range 3..=2"),
+        "{err}"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,70 @@
+//! Time compiling generated libraries of growing size, to see how it scales with
+//! the number of words.
+//!
+//!     cargo run --release --features compile --example bench_compile
+
+use std::time::Instant;
+
+use food_generator2::syntax::{LibraryBuilder, RulePart};
+
+/// Words made of `len` characters from a fixed pool, none a prefix of another
+/// since they all have the same length.
+fn words(seed: u32, count: usize, len: usize) -> Vec<String> {
+    let pool: Vec<char> = ('\u{4e00}'..='\u{4fff}').collect();
+    let mut state = seed;
+    let mut seen = std::collections::HashSet::new();
+    while seen.len() < count {
+        let word: String = (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                pool[(state >> 8) as usize % pool.len()]
+            })
+            .collect();
+        seen.insert(word);
+    }
+    let mut words: Vec<String> = seen.into_iter().collect();
+    words.sort();
+    words
+}
+
+fn library(total: usize) -> LibraryBuilder {
+    let mut builder = LibraryBuilder::new();
+    let sec = |name: &str| RulePart::Section(name.into());
+    let text = |t: &str| RulePart::Text(t.into());
+
+    // 前两条规则要看完整个形容词才能分开
+    builder
+        .add_rule(
+            "entry",
+            [sec("形容词"), text("的"), sec("名词"), sec("动词")],
+        )
+        .add_rule("entry", [sec("形容词"), text("地"), sec("动词")])
+        .add_rule("entry", [sec("名词"), text("在"), sec("地点"), sec("动词")])
+        .add_rule("短语", [sec("形容词"), sec("名词")])
+        .add_rule("entry", [text("「"), sec("短语"), text("」了")])
+        .add_rule("entry", [text("「"), sec("短语"), text("」吗")]);
+    for (i, name) in ["形容词", "名词", "动词", "地点"].into_iter().enumerate() {
+        for word in words(i as u32 + 1, total / 4, 3) {
+            builder.add_word(name, word);
+        }
+    }
+    builder
+}
+
+fn main() {
+    for total in [4000, 8000, 16000, 32000, 64000] {
+        let builder = library(total);
+        let start = Instant::now();
+        let map = builder.build().unwrap();
+        let elapsed = start.elapsed();
+
+        let data = b"food generator";
+        let text = food_generator2::encode(&map, data).unwrap();
+        assert_eq!(food_generator2::decode(&map, &text).unwrap(), data);
+        println!(
+            "{total:>6} words: {:>8.1} ms, {:.2} µs per word",
+            elapsed.as_secs_f64() * 1000.0,
+            elapsed.as_secs_f64() * 1e6 / total as f64
+        );
+    }
+}
@@ -28,6 +28,11 @@ impl BitWriter {
         }
     }
 
+    /// Whole bytes written so far.
+    pub fn written(&self) -> &[u8] {
+        &self.decoded
+    }
+
     pub fn finish(mut self) -> Vec<u8> {
         // 忽略 `empty_bits`
 
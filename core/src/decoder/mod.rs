@@ -1,55 +1,90 @@
 use anyhow::{anyhow, Result};
 use bits::BitWriter;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::syntax::{Layer, Section, Seg, SerializeMap};
+use crate::{
+    generator::GeneratorTable,
+    syntax::{fold_case, Layer, Section, Seg, SerializeMap},
+};
 
 mod bits;
+mod search;
 
 struct Decoder<'a> {
     input: &'a str,
     output: BitWriter,
+    generators: &'a GeneratorTable,
 }
 
-impl Decoder<'_> {
-    fn decode(&mut self, map: &SerializeMap, section: &Section) -> Result<()> {
-        let nth_rule = self.match_index(section)?;
-        self.write_msg(nth_rule, section.encoder.len());
+impl<'a> Decoder<'a> {
+    fn decode(&mut self, map: &SerializeMap, section: &Section) -> Result<usize> {
+        let nth_rule = match_index(map, self.generators, section, self.input)?;
+        self.write_msg(nth_rule, section.encoder.len(), |s, e| section.split(s, e));
 
-        for seg in &section.encoder[nth_rule] {
+        let rule = &section.encoder[nth_rule];
+        let mut spans: Vec<&'a str> = Vec::with_capacity(rule.len());
+        let mut choices: Vec<usize> = Vec::with_capacity(rule.len());
+        for seg in rule {
+            let start = self.input;
+            let mut choice = 0;
             match seg {
-                Seg::Text(txt) => {
-                    if self.input.starts_with(&**txt) {
-                        self.input = &self.input[txt.len()..];
-                    } else {
-                        return Err(self.error());
-                    }
-                }
-                Seg::Use(r) => self.decode(map, &map[*r as usize])?,
+                Seg::Text(txt) => self.expect_text(txt, section.ignore_case)?,
+                Seg::Use(r) => choice = self.decode(map, &map[*r as usize])?,
+                Seg::Generate(name) => self.decode_generator(name)?,
+                &Seg::Recall(r) => self.expect_derived(spans[r as usize], section.ignore_case)?,
+                Seg::Attr { seg, values } => self
+                    .expect_derived(values[choices[*seg as usize]].as_str(), section.ignore_case)?,
             }
+            spans.push(&start[..start.len() - self.input.len()]);
+            choices.push(choice);
         }
 
-        Ok(())
+        Ok(nth_rule)
     }
 
-    fn match_index(&self, section: &Section) -> Result<usize> {
-        let mut chars = self.input.chars();
-        let mut layer = &section.decoder;
+    /// Match text that was determined by an earlier choice of the same rule.
+    fn expect_derived(&mut self, expected: &str, ignore_case: bool) -> Result<()> {
+        match strip_prefix(self.input, expected, ignore_case) {
+            Some(rest) => {
+                self.input = rest;
+                Ok(())
+            }
+            None => {
+                let (display_str, omit) = truncate_str_after_chars(self.input, 10, "...");
+                Err(anyhow!(
+                    "expect `{expected}` determined by an earlier word, \
+                    but found `{display_str}{omit}`"
+                ))
+            }
+        }
+    }
 
-        for ch in &mut chars {
-            match layer {
-                Layer::Branch(b) => match b.get(&ch) {
-                    Some(l) => layer = l,
-                    None => {
-                        let (display_str, omit) =
-                            truncate_str_after_chars(chars.as_str(), 10, "...");
-                        return Err(anyhow!("found unexpected character `{ch}` when parsing at `{display_str}{omit}`"));
-                    }
-                },
-                &Layer::Certain(c) => return Ok(c as _),
+    fn expect_text(&mut self, txt: &str, ignore_case: bool) -> Result<()> {
+        match strip_prefix(self.input, txt, ignore_case) {
+            Some(rest) => {
+                self.input = rest;
+                Ok(())
             }
+            None => Err(self.error()),
         }
+    }
+
+    fn decode_generator(&mut self, name: &str) -> Result<()> {
+        let generator = self
+            .generators
+            .get(name)
+            .ok_or_else(|| anyhow!("generator `{name}` is not registered"))?;
 
-        return Err(anyhow!("unexpected end of stream"));
+        match generator.decode(self.input) {
+            Some((value, consumed))
+                if value < generator.count() && self.input.is_char_boundary(consumed) =>
+            {
+                self.write_msg(value as _, generator.count() as _, |s, e| (s + e) / 2);
+                self.input = &self.input[consumed..];
+                Ok(())
+            }
+            _ => Err(self.error()),
+        }
     }
 
     fn error(&self) -> anyhow::Error {
@@ -57,12 +92,12 @@ impl Decoder<'_> {
         anyhow!("error when parsing at `{display_str}{omit}`")
     }
 
-    fn write_msg(&mut self, msg: usize, total: usize) {
+    fn write_msg(&mut self, msg: usize, total: usize, split: impl Fn(usize, usize) -> usize) {
         let mut start = 0;
         let mut end = total;
 
         while end - start > 1 {
-            let mid = (start + end) / 2;
+            let mid = split(start, end);
             if msg < mid {
                 self.output.write(false);
                 end = mid;
@@ -73,6 +108,22 @@ impl Decoder<'_> {
         }
     }
 
+    /// Write the choices of a parse found by [`search::parse`].
+    fn write_tree(&mut self, map: &SerializeMap, tree: &search::Tree) {
+        let section = &map[tree.section as usize];
+        self.write_msg(tree.rule as _, section.encoder.len(), |s, e| {
+            section.split(s, e)
+        });
+        for child in &tree.children {
+            match child {
+                search::Choice::Rule(tree) => self.write_tree(map, tree),
+                &search::Choice::Value(value, count) => {
+                    self.write_msg(value as _, count as _, |s, e| (s + e) / 2)
+                }
+            }
+        }
+    }
+
     fn ended(&self) -> bool {
         self.input.is_empty()
     }
@@ -84,18 +135,122 @@ impl Decoder<'_> {
 }
 
 pub fn decode(map: &SerializeMap, s: &str) -> Result<Vec<u8>> {
+    decode_with(map, &GeneratorTable::new(), s)
+}
+
+pub fn decode_with(map: &SerializeMap, generators: &GeneratorTable, s: &str) -> Result<Vec<u8>> {
+    // 库里的文本在编译时已经是NFC
+    let normalized: String = s.nfc().collect();
     let mut decoder = Decoder {
-        input: s,
+        input: &normalized,
         output: BitWriter::new(),
+        generators,
     };
 
-    while !decoder.ended() {
-        decoder.decode(map, &map[0])?;
+    if map.iter().any(|sec| matches!(sec.decoder, Layer::Search)) {
+        for tree in search::parse(map, generators, &normalized)? {
+            decoder.write_tree(map, &tree);
+        }
+        decoder.input = "";
+    } else {
+        while !decoder.ended() {
+            decoder.decode(map, &map[0])?;
+        }
     }
 
     Ok(decoder.finish())
 }
 
+/// The whole bytes read from the texts of the entry section that parse at the start
+/// of `s`, to tell what wrote a text that does not parse to the end. Libraries decoded
+/// by searching read all or nothing, so nothing is read for them.
+pub(crate) fn decode_prefix(map: &SerializeMap, generators: &GeneratorTable, s: &str) -> Vec<u8> {
+    let normalized: String = s.nfc().collect();
+    let mut decoder = Decoder {
+        input: &normalized,
+        output: BitWriter::new(),
+        generators,
+    };
+    if map.iter().any(|sec| matches!(sec.decoder, Layer::Search)) {
+        return Vec::new();
+    }
+
+    let mut complete = 0;
+    while !decoder.ended() && decoder.decode(map, &map[0]).is_ok() {
+        complete = decoder.output.written().len();
+    }
+    decoder.output.written()[..complete].to_vec()
+}
+
+/// The rule of `section` that the start of `input` must be written with.
+fn match_index(
+    map: &SerializeMap,
+    generators: &GeneratorTable,
+    section: &Section,
+    input: &str,
+) -> Result<usize> {
+    let mut chars = input.chars();
+    let mut layer = &section.decoder;
+
+    loop {
+        match layer {
+            Layer::Branch(b) => {
+                let Some(ch) = chars.next() else {
+                    return Err(anyhow!("unexpected end of stream"));
+                };
+
+                // 键是否折叠过由写下这个字的段落决定，不看当前段落
+                let found = match b.get(&ch) {
+                    None => b
+                        .get(&fold_case(ch))
+                        .filter(|l| matches!(l, Layer::Folded(_))),
+                    found => found,
+                };
+                match found {
+                    Some(l) => layer = l,
+                    None => {
+                        let (display_str, omit) =
+                            truncate_str_after_chars(chars.as_str(), 10, "...");
+                        return Err(anyhow!("found unexpected character `{ch}` when parsing at `{display_str}{omit}`"));
+                    }
+                }
+            }
+            &Layer::Certain(c) => return Ok(c as _),
+            Layer::Folded(inner) => layer = inner,
+            Layer::Through { section, next } => {
+                // 先把这个段落整个读过去，读到的东西不用记下
+                let mut probe = Decoder {
+                    input: chars.as_str(),
+                    output: BitWriter::new(),
+                    generators,
+                };
+                probe.decode(map, &map[*section as usize])?;
+                chars = probe.input.chars();
+                layer = next;
+            }
+            Layer::Search => {
+                return Err(anyhow!(
+                    "rules of this section can only be told apart by searching"
+                ))
+            }
+        }
+    }
+}
+
+fn strip_prefix<'a>(s: &'a str, prefix: &str, ignore_case: bool) -> Option<&'a str> {
+    if !ignore_case {
+        return s.strip_prefix(prefix);
+    }
+
+    let mut chars = s.chars();
+    for expected in prefix.chars() {
+        if fold_case(chars.next()?) != fold_case(expected) {
+            return None;
+        }
+    }
+    Some(chars.as_str())
+}
+
 fn truncate_str_after_chars<'a, 'b>(
     s: &'a str,
     chars: usize,
@@ -106,3 +261,131 @@ fn truncate_str_after_chars<'a, 'b>(
         None => (s, ""),
     }
 }
+
+#[test]
+fn test_recall() {
+    use std::collections::BTreeMap;
+
+    use crate::share_str::ShareStr;
+
+    let map = vec![
+        Section {
+            encoder: vec![vec![
+                Seg::Use(1),
+                Seg::Text(ShareStr::new("和")),
+                Seg::Recall(0),
+            ]],
+            decoder: Layer::Certain(0),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        },
+        Section {
+            encoder: vec![
+                vec![Seg::Text(ShareStr::new("甲"))],
+                vec![Seg::Text(ShareStr::new("乙"))],
+            ],
+            decoder: Layer::Branch(BTreeMap::from([
+                ('甲', Layer::Certain(0)),
+                ('乙', Layer::Certain(1)),
+            ])),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        },
+    ];
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(decode(&map, &encoded).unwrap(), b"fg2");
+    assert!(decode(&map, "甲和乙").is_err());
+}
+
+#[test]
+fn test_attr() {
+    use std::collections::BTreeMap;
+
+    use crate::share_str::ShareStr;
+
+    let text = |s| Seg::Text(ShareStr::new(s));
+    let map = vec![
+        Section {
+            encoder: vec![vec![
+                Seg::Use(1),
+                text("一"),
+                Seg::Attr {
+                    seg: 0,
+                    values: vec![ShareStr::new("个"), ShareStr::new("台")],
+                },
+            ]],
+            decoder: Layer::Certain(0),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        },
+        Section {
+            encoder: vec![vec![text("桶")], vec![text("电视机")]],
+            decoder: Layer::Branch(BTreeMap::from([
+                ('桶', Layer::Certain(0)),
+                ('电', Layer::Certain(1)),
+            ])),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        },
+    ];
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(decode(&map, &encoded).unwrap(), b"fg2");
+    assert!(decode(&map, "桶一台").is_err());
+}
+
+#[test]
+fn test_normalize_input() {
+    use std::collections::BTreeMap;
+
+    use crate::share_str::ShareStr;
+
+    let map = vec![Section {
+        encoder: vec![
+            vec![Seg::Text(ShareStr::new("café"))],
+            vec![Seg::Text(ShareStr::new("thé"))],
+        ],
+        decoder: Layer::Branch(BTreeMap::from([
+            ('c', Layer::Certain(0)),
+            ('t', Layer::Certain(1)),
+        ])),
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    }];
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    let decomposed: String = encoded.nfd().collect();
+    assert_ne!(encoded, decomposed);
+    assert_eq!(decode(&map, &decomposed).unwrap(), b"fg2");
+}
+
+#[test]
+fn test_weights() {
+    use std::collections::BTreeMap;
+
+    use crate::share_str::ShareStr;
+
+    let map = vec![Section {
+        encoder: ["甲", "乙", "丙"]
+            .map(|s| vec![Seg::Text(ShareStr::new(s))])
+            .into(),
+        decoder: Layer::Branch(BTreeMap::from([
+            ('甲', Layer::Certain(0)),
+            ('乙', Layer::Certain(1)),
+            ('丙', Layer::Certain(2)),
+        ])),
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: vec![2, 1, 1],
+    }];
+    assert_eq!(map[0].split(0, 3), 1);
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(decode(&map, &encoded).unwrap(), b"fg2");
+}
@@ -0,0 +1,372 @@
+//! Decoding by trying every way to read the text, for libraries with sections whose
+//! rules cannot be told apart by looking ahead, like `饼` and `饼干`. The compiler has
+//! made sure that at most one way reads the whole text.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    generator::GeneratorTable,
+    syntax::{Layer, Section, Seg, SerializeMap},
+};
+
+use super::{match_index, strip_prefix, truncate_str_after_chars};
+
+/// The rule chosen for one section, with the choices made inside it in encoding order.
+pub struct Tree {
+    pub section: u32,
+    pub rule: u32,
+    pub children: Vec<Choice>,
+}
+
+#[derive(Clone)]
+pub enum Choice {
+    Rule(Rc<Tree>),
+    /// Value of a generator, and how many values it has.
+    Value(u32, u32),
+}
+
+/// One way to read a section, ending at byte `end` of the input.
+#[derive(Clone)]
+struct Parse {
+    end: usize,
+    tree: Rc<Tree>,
+}
+
+/// A rule read up to some segment.
+#[derive(Clone)]
+struct Partial {
+    pos: usize,
+    children: Vec<Choice>,
+    spans: Vec<(usize, usize)>,
+    choices: Vec<usize>,
+}
+
+struct Parser<'a> {
+    map: &'a SerializeMap,
+    generators: &'a GeneratorTable,
+    input: &'a str,
+    memo: HashMap<(u32, usize), Rc<[Parse]>>,
+    /// Furthest position any text matched, where the error is reported.
+    furthest: usize,
+}
+
+/// Read `input` as texts of the entry section one after another.
+pub fn parse(
+    map: &SerializeMap,
+    generators: &GeneratorTable,
+    input: &str,
+) -> Result<Vec<Rc<Tree>>> {
+    let generated = map.iter().flat_map(|sec| sec.encoder.iter().flatten());
+    for seg in generated {
+        if let Seg::Generate(name) = seg {
+            if generators.get(name).is_none() {
+                return Err(anyhow!("generator `{name}` is not registered"));
+            }
+        }
+    }
+
+    let mut parser = Parser {
+        map,
+        generators,
+        input,
+        memo: HashMap::new(),
+        furthest: 0,
+    };
+
+    // 每个位置记下是从哪里读过来的
+    let mut from: HashMap<usize, (usize, Rc<Tree>)> = HashMap::new();
+    let mut pending = BTreeSet::from([0]);
+    while let Some(pos) = pending.pop_first() {
+        if pos == input.len() {
+            let mut trees = Vec::new();
+            let mut pos = pos;
+            while pos > 0 {
+                let (start, tree) = &from[&pos];
+                trees.push(tree.clone());
+                pos = *start;
+            }
+            trees.reverse();
+            return Ok(trees);
+        }
+
+        for parse in parser.section(0, pos).iter() {
+            if parse.end > pos && !from.contains_key(&parse.end) {
+                from.insert(parse.end, (pos, parse.tree.clone()));
+                pending.insert(parse.end);
+            }
+        }
+    }
+
+    let (display_str, omit) = truncate_str_after_chars(&input[parser.furthest..], 10, "...");
+    Err(anyhow!("error when parsing at `{display_str}{omit}`"))
+}
+
+impl Parser<'_> {
+    fn section(&mut self, index: u32, pos: usize) -> Rc<[Parse]> {
+        if let Some(found) = self.memo.get(&(index, pos)) {
+            return found.clone();
+        }
+
+        let section = &self.map[index as usize];
+        // 能靠前瞻确定规则的段落只需要试一条
+        let rules = match section.decoder {
+            Layer::Search => 0..section.encoder.len(),
+            _ => match match_index(self.map, self.generators, section, &self.input[pos..]) {
+                Ok(rule) => rule..rule + 1,
+                Err(_) => 0..0,
+            },
+        };
+
+        let mut found = Vec::new();
+        for rule in rules {
+            for partial in self.rule(section, rule, pos) {
+                found.push(Parse {
+                    end: partial.pos,
+                    tree: Rc::new(Tree {
+                        section: index,
+                        rule: rule as _,
+                        children: partial.children,
+                    }),
+                });
+            }
+        }
+
+        let found: Rc<[Parse]> = found.into();
+        self.memo.insert((index, pos), found.clone());
+        found
+    }
+
+    /// Every way to read the rule starting at `pos`.
+    fn rule(&mut self, section: &Section, rule: usize, pos: usize) -> Vec<Partial> {
+        let mut partials = vec![Partial {
+            pos,
+            children: Vec::new(),
+            spans: Vec::new(),
+            choices: Vec::new(),
+        }];
+
+        for seg in &section.encoder[rule] {
+            let mut next = Vec::new();
+            for partial in partials {
+                let rest = &self.input[partial.pos..];
+                let expected = match seg {
+                    Seg::Text(txt) => Some(txt.as_str()),
+                    &Seg::Recall(r) => {
+                        let (start, end) = partial.spans[r as usize];
+                        Some(&self.input[start..end])
+                    }
+                    Seg::Attr { seg, values } => {
+                        Some(values[partial.choices[*seg as usize]].as_str())
+                    }
+                    Seg::Use(_) | Seg::Generate(_) => None,
+                };
+
+                if let Some(expected) = expected {
+                    if let Some(rest) = strip_prefix(rest, expected, section.ignore_case) {
+                        next.push(self.advance(&partial, rest, None, 0));
+                    }
+                    continue;
+                }
+
+                match seg {
+                    &Seg::Use(r) => {
+                        for parse in self.section(r, partial.pos).iter() {
+                            let rest = &self.input[parse.end..];
+                            let choice = parse.tree.rule as usize;
+                            next.push(self.advance(
+                                &partial,
+                                rest,
+                                Some(Choice::Rule(parse.tree.clone())),
+                                choice,
+                            ));
+                        }
+                    }
+                    Seg::Generate(name) => {
+                        let Some(generator) = self.generators.get(name) else {
+                            continue;
+                        };
+                        match generator.decode(rest) {
+                            Some((value, consumed))
+                                if value < generator.count() && rest.is_char_boundary(consumed) =>
+                            {
+                                let choice = Choice::Value(value, generator.count());
+                                next.push(self.advance(
+                                    &partial,
+                                    &rest[consumed..],
+                                    Some(choice),
+                                    0,
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            partials = next;
+        }
+
+        partials
+    }
+
+    fn advance(
+        &mut self,
+        partial: &Partial,
+        rest: &str,
+        child: Option<Choice>,
+        choice: usize,
+    ) -> Partial {
+        let end = self.input.len() - rest.len();
+        self.furthest = self.furthest.max(end);
+
+        let mut next = partial.clone();
+        next.spans.push((partial.pos, end));
+        next.choices.push(choice);
+        next.children.extend(child);
+        next.pos = end;
+        next
+    }
+}
+
+#[cfg(test)]
+fn searched(words: &[&str]) -> Section {
+    use crate::share_str::ShareStr;
+
+    Section {
+        encoder: words
+            .iter()
+            .map(|w| vec![Seg::Text(ShareStr::new(w))])
+            .collect(),
+        decoder: Layer::Search,
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    }
+}
+
+#[test]
+fn test_overlap() {
+    use std::collections::BTreeMap;
+
+    let map = vec![
+        Section {
+            encoder: vec![vec![Seg::Use(1), Seg::Use(2)]],
+            decoder: Layer::Certain(0),
+            ..searched(&[])
+        },
+        searched(&["饼", "饼干"]),
+        Section {
+            decoder: Layer::Branch(BTreeMap::from([
+                ('干', Layer::Certain(0)),
+                ('汤', Layer::Certain(1)),
+            ])),
+            ..searched(&["干饭", "汤"])
+        },
+    ];
+    let rules = |input: &str| -> Vec<u32> {
+        let trees = parse(&map, &GeneratorTable::new(), input).unwrap();
+        let children = trees.iter().flat_map(|tree| &tree.children);
+        children
+            .map(|child| match child {
+                Choice::Rule(tree) => tree.rule,
+                Choice::Value(..) => unreachable!(),
+            })
+            .collect()
+    };
+
+    // 读到“饼”时还不知道“干”属于哪个段落，要看后面的字
+    assert_eq!(rules("饼干饭"), [0, 0]);
+    assert_eq!(rules("饼干汤"), [1, 1]);
+    assert_eq!(rules("饼汤饼干饭"), [0, 1, 0, 0]);
+    assert!(parse(&map, &GeneratorTable::new(), "饼干干").is_err());
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(super::decode(&map, &encoded).unwrap(), b"fg2");
+}
+
+#[test]
+fn test_nested_search() {
+    use std::collections::BTreeMap;
+
+    use crate::share_str::ShareStr;
+
+    let map = vec![
+        Section {
+            encoder: vec![vec![Seg::Use(1), Seg::Use(3)]],
+            decoder: Layer::Certain(0),
+            ..searched(&[])
+        },
+        // 搜索的段落里又用到搜索的段落，两层都要回头重试
+        Section {
+            encoder: vec![
+                vec![Seg::Use(2)],
+                vec![Seg::Use(2), Seg::Text(ShareStr::new("干"))],
+            ],
+            ..searched(&[])
+        },
+        searched(&["甜", "甜饼"]),
+        Section {
+            decoder: Layer::Branch(BTreeMap::from([
+                ('干', Layer::Certain(0)),
+                ('汤', Layer::Certain(1)),
+            ])),
+            ..searched(&["干饭", "汤"])
+        },
+    ];
+
+    for input in ["甜饼干饭", "甜饼干汤", "甜汤", "甜干汤"] {
+        assert!(
+            parse(&map, &GeneratorTable::new(), input).is_ok(),
+            "{input}"
+        );
+    }
+    assert!(parse(&map, &GeneratorTable::new(), "甜饼饭").is_err());
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(super::decode(&map, &encoded).unwrap(), b"fg2");
+}
+
+#[test]
+fn test_recall_in_search() {
+    use crate::share_str::ShareStr;
+
+    let text = |s| Seg::Text(ShareStr::new(s));
+    let map = vec![
+        Section {
+            encoder: vec![vec![Seg::Use(1), text("。")]],
+            decoder: Layer::Certain(0),
+            ..searched(&[])
+        },
+        Section {
+            encoder: vec![
+                vec![Seg::Use(2), text("和"), Seg::Recall(0)],
+                vec![Seg::Use(2), text("和饭")],
+            ],
+            ..searched(&[])
+        },
+        searched(&["饼", "饼干"]),
+    ];
+
+    // 重复的词必须和前面读到的一样长
+    for input in ["饼干和饼干。", "饼和饼。", "饼干和饭。"] {
+        assert!(
+            parse(&map, &GeneratorTable::new(), input).is_ok(),
+            "{input}"
+        );
+    }
+    let err = parse(&map, &GeneratorTable::new(), "饼干和饼。")
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().starts_with("error when parsing at"),
+        "{err}"
+    );
+
+    let encoded = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(super::decode(&map, &encoded).unwrap(), b"fg2");
+}
@@ -4,6 +4,7 @@ pub struct BitReader<'a> {
     current: u8,
     rest_bits: u8,
     source: Source<'a>,
+    reading_token: bool,
 }
 
 enum Source<'a> {
@@ -22,6 +23,7 @@ impl<'a> BitReader<'a> {
             } else {
                 Source::Trailing(LcgU8::new(0))
             },
+            reading_token: false,
         }
     }
 
@@ -31,6 +33,9 @@ impl<'a> BitReader<'a> {
             self.rest_bits = 7;
         } else {
             self.rest_bits -= 1;
+            if self.rest_bits == 0 {
+                self.reading_token = false;
+            }
         }
 
         let result = self.current & 1 != 0;
@@ -51,13 +56,42 @@ impl<'a> BitReader<'a> {
             }
             &mut Source::EndToken(token) => {
                 self.source = Source::Trailing(LcgU8::new(token));
+                self.reading_token = true;
                 token
             }
-            Source::Trailing(lcg) => lcg.next(),
+            Source::Trailing(lcg) => {
+                self.reading_token = false;
+                lcg.next()
+            }
         }
     }
 
     pub fn ended(&self) -> bool {
-        matches!(self.source, Source::Trailing(_))
+        // 结束标记必须完整写出，否则解码时找不到它
+        matches!(self.source, Source::Trailing(_)) && !self.reading_token
+    }
+}
+
+#[test]
+fn test_end_token() {
+    let data = b"0123456789abcdef";
+    // 编码器每写一句才看一次是否读完，一句用掉的比特数不定
+    for chunk in 1..=16 {
+        let mut reader = BitReader::new(data);
+        let mut bits = Vec::new();
+        while !reader.ended() {
+            bits.extend((0..chunk).map(|_| reader.get()));
+        }
+
+        let bytes: Vec<u8> = bits
+            .chunks_exact(8)
+            .map(|byte| byte.iter().rev().fold(0, |acc, &bit| acc << 1 | bit as u8))
+            .collect();
+        assert_eq!(&bytes[..data.len()], data);
+        assert_eq!(
+            bytes.get(data.len()),
+            Some(&!data[data.len() - 1]),
+            "{chunk}"
+        );
     }
 }
@@ -1,6 +1,12 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
 use bits::BitReader;
 
-use crate::syntax::{Section, Seg, SerializeMap};
+use crate::{
+    generator::GeneratorTable,
+    syntax::{Section, Seg, SerializeMap},
+};
 
 mod bits;
 mod lcg;
@@ -8,40 +14,85 @@ mod lcg;
 struct Encoder<'a> {
     input: BitReader<'a>,
     output: String,
+    generators: &'a GeneratorTable,
 }
 
 impl<'a> Encoder<'a> {
-    fn encode(&mut self, map: &SerializeMap, section: &Section) {
-        let mut range = &section.encoder[..];
-        assert!(!range.is_empty());
+    fn encode(&mut self, map: &SerializeMap, section: &Section) -> Result<usize> {
+        assert!(!section.encoder.is_empty());
+        let nth_rule = self.choose(section.encoder.len(), |s, e| section.split(s, e));
+        let rule = &section.encoder[nth_rule];
 
-        let rule = loop {
-            if let [rule] = range {
-                break rule;
+        let mut spans: Vec<Range<usize>> = Vec::with_capacity(rule.len());
+        let mut choices: Vec<usize> = Vec::with_capacity(rule.len());
+        for seg in rule {
+            let start = self.output.len();
+            let mut choice = 0;
+            match seg {
+                Seg::Text(txt) => self.output.push_str(txt),
+                Seg::Use(r) => choice = self.encode(map, &map[*r as usize])?,
+                Seg::Generate(name) => {
+                    let generator = self
+                        .generators
+                        .get(name)
+                        .ok_or_else(|| anyhow!("generator `{name}` is not registered"))?;
+                    if generator.count() == 0 {
+                        return Err(anyhow!("generator `{name}` has no values"));
+                    }
+                    let value = self.choose(generator.count() as usize, |s, e| (s + e) / 2);
+                    self.output.push_str(&generator.encode(value as u32));
+                }
+                &Seg::Recall(r) => {
+                    let captured = self.output[spans[r as usize].clone()].to_owned();
+                    self.output.push_str(&captured);
+                }
+                Seg::Attr { seg, values } => {
+                    self.output
+                        .push_str(values[choices[*seg as usize]].as_str());
+                }
             }
+            spans.push(start..self.output.len());
+            choices.push(choice);
+        }
 
-            let (small, large) = range.split_at(range.len() / 2);
-            range = if self.input.get() { large } else { small };
-        };
+        Ok(nth_rule)
+    }
 
-        for seg in rule {
-            match seg {
-                Seg::Text(txt) => self.output.push_str(&txt),
-                Seg::Use(r) => self.encode(map, &map[*r as usize]),
+    fn choose(&mut self, total: usize, split: impl Fn(usize, usize) -> usize) -> usize {
+        let mut start = 0;
+        let mut end = total;
+
+        while end - start > 1 {
+            let mid = split(start, end);
+            if self.input.get() {
+                start = mid;
+            } else {
+                end = mid;
             }
         }
+
+        start
     }
 }
 
-pub fn encode(map: &SerializeMap, input: &[u8]) -> String {
+pub fn encode(map: &SerializeMap, input: &[u8]) -> Result<String> {
+    encode_with(map, &GeneratorTable::new(), input)
+}
+
+pub fn encode_with(
+    map: &SerializeMap,
+    generators: &GeneratorTable,
+    input: &[u8],
+) -> Result<String> {
     let mut encoder = Encoder {
         input: BitReader::new(input),
         output: String::new(),
+        generators,
     };
 
     while !encoder.input.ended() {
-        encoder.encode(map, &map[0]);
+        encoder.encode(map, &map[0])?;
     }
 
-    encoder.output
+    Ok(encoder.output)
 }
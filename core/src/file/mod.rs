@@ -1,14 +1,90 @@
-use std::{fs, path::Path};
+use std::{fs, hash::Hasher, path::Path};
 
-use crate::syntax::SerializeMap;
+use crate::{fnv::Fnv64, syntax::SerializeMap};
 use anyhow::{anyhow, Result};
 
-pub use read::read_lib;
-pub use write::save_lib;
+pub use read::{read_lib, read_library};
+pub use write::{save_lib, save_library};
 
 mod read;
 mod write;
 
+/// Start of every library file since format version 2. Files of version 1 are a bare
+/// zlib stream, which never starts like this.
+const MAGIC: &[u8; 4] = b"FG2\x1b";
+/// Newest format this crate writes and reads.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Tags of the metadata fields in the header of a version 2 file.
+const META_NAME: u32 = 0;
+const META_DESCRIPTION: u32 = 1;
+const META_AUTHOR: u32 = 2;
+const META_CREATED_BY: u32 = 3;
+const META_SOURCE_HASH: u32 = 4;
+const META_SECTION_NAMES: u32 = 5;
+const META_FEATURES: u32 = 6;
+/// Feature flag: the data is deflated before it is encoded.
+const FEATURE_COMPRESSION: u32 = 1;
+/// Feature flag: texts start with the fingerprint of the library, see [`crate::fingerprint`].
+const FEATURE_FINGERPRINT: u32 = 2;
+
+/// Tag of the optional extension block holding one varint of flags per section.
+const EXT_SECTION_FLAGS: u32 = 0;
+/// Tag of the optional extension block holding the tags of each rule.
+const EXT_RULE_TAGS: u32 = 1;
+/// Tag of the optional extension block holding the weights of each rule.
+const EXT_RULE_WEIGHTS: u32 = 2;
+const FLAG_IGNORE_CASE: u32 = 1;
+
+/// A compiled library and what is known about it.
+pub struct Library {
+    pub map: SerializeMap,
+    pub meta: Metadata,
+    /// Format version of the file it was read from, [`FORMAT_VERSION`] if it was not read.
+    pub version: u32,
+}
+
+impl Library {
+    pub fn new(map: SerializeMap, meta: Metadata) -> Self {
+        Library {
+            map,
+            meta,
+            version: FORMAT_VERSION,
+        }
+    }
+
+    /// See [`content_hash`].
+    pub fn content_hash(&self) -> u64 {
+        content_hash(&self.map)
+    }
+}
+
+/// Hash of the sections of a library, the same for libraries that encode and decode
+/// the same way whatever file they were read from, and whatever their metadata says.
+pub fn content_hash(map: &SerializeMap) -> u64 {
+    let mut hasher = Fnv64::default();
+    hasher.write(&write::put_body(map));
+    hasher.finish()
+}
+
+/// What a library file says about the library, all empty for files of version 1.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    /// Program and version that wrote the file.
+    pub created_by: Option<String>,
+    /// Hash of the source files the library was compiled from.
+    pub source_hash: Option<u64>,
+    /// Qualified names of the sections, in the order of the map.
+    pub section_names: Vec<String>,
+    /// Whether data is deflated before encoding, which decoding has to undo.
+    pub compression: bool,
+    /// Whether texts start with the fingerprint of the library.
+    pub fingerprint: bool,
+}
+
 pub fn save_lib_to_file<P>(lib: &SerializeMap, path: P) -> std::io::Result<()>
 where
     P: AsRef<Path>,
@@ -17,12 +93,27 @@ where
     fs::write(path, file)
 }
 
+pub fn save_library_to_file<P>(lib: &Library, path: P) -> std::io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    fs::write(path, write::save_library(&lib.map, &lib.meta))
+}
+
 pub fn read_lib_from_file<P>(path: P) -> Result<SerializeMap>
 where
     P: AsRef<Path>,
 {
+    read_library_from_file(path).map(|lib| lib.map)
+}
+
+pub fn read_library_from_file<P>(path: P) -> Result<Library>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
     let file = fs::read(path)?;
-    read::read_lib(&file).ok_or_else(|| anyhow!("illegal file content"))
+    read::read_library(&file).map_err(|err| anyhow!("cannot read `{}`: {err}", path.display()))
 }
 
 #[test]
@@ -36,3 +127,83 @@ fn test_varint() {
     assert!(data.is_empty());
     assert_eq!(value, read);
 }
+
+#[test]
+fn test_section_flags() {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    let section = |ignore_case| Section {
+        encoder: vec![vec![Seg::Text(ShareStr::new("Cat"))]],
+        decoder: Layer::Certain(0),
+        ignore_case,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    };
+
+    let plain = write::save_lib(&[section(false)]);
+    assert!(!read::read_lib(&plain).unwrap()[0].ignore_case);
+
+    let read = read::read_lib(&write::save_lib(&[section(false), section(true)])).unwrap();
+    assert!(!read[0].ignore_case);
+    assert!(read[1].ignore_case);
+
+    // 折叠过的键在文件里也要标出来
+    let folded = Section {
+        decoder: Layer::Branch(BTreeMap::from([(
+            'c',
+            Layer::Folded(Layer::Certain(0).into()),
+        )])),
+        ..section(true)
+    };
+    let read = read::read_lib(&write::save_lib(&[folded])).unwrap();
+    assert!(matches!(
+        &read[0].decoder,
+        Layer::Branch(b) if matches!(b[&'c'], Layer::Folded(_))
+    ));
+}
+
+#[test]
+fn test_container() {
+    use crate::{
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    let map = vec![Section {
+        encoder: vec![vec![Seg::Text(ShareStr::new("甲"))]],
+        decoder: Layer::Certain(0),
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    }];
+    let meta = Metadata {
+        name: Some("测试".into()),
+        source_hash: Some(0x0123_4567_89ab_cdef),
+        section_names: vec!["entry".into()],
+        compression: true,
+        ..Metadata::default()
+    };
+
+    let file = write::save_library(&map, &meta);
+    assert!(file.starts_with(MAGIC));
+    let lib = read::read_library(&file).unwrap();
+    assert_eq!(lib.version, FORMAT_VERSION);
+    assert_eq!(lib.meta, meta);
+    assert_eq!(lib.map.len(), 1);
+
+    // 第1版的文件只有压缩过的段落
+    let legacy = read::read_library(&write::save_body(&map)).unwrap();
+    assert_eq!(legacy.version, 1);
+    assert_eq!(legacy.meta, Metadata::default());
+
+    let mut newer = MAGIC.to_vec();
+    write::put_varint(&mut newer, FORMAT_VERSION + 1);
+    let err = read::read_library(&newer).err().unwrap().to_string();
+    assert!(err.contains("format version 3"), "{err}");
+    assert!(read::read_library(b"not a library").is_err());
+}
@@ -1,6 +1,7 @@
 use core::str;
-use std::{collections::HashMap, io::Read};
+use std::{collections::BTreeMap, io::Read};
 
+use anyhow::{anyhow, Result};
 use bytes::Buf;
 use inflate::inflate_bytes_zlib;
 
@@ -9,10 +10,92 @@ use crate::{
     syntax::{Layer, Section, Seg, SerializeMap},
 };
 
+use super::{
+    Library, Metadata, EXT_RULE_TAGS, EXT_RULE_WEIGHTS, EXT_SECTION_FLAGS, FEATURE_COMPRESSION,
+    FEATURE_FINGERPRINT, FLAG_IGNORE_CASE, FORMAT_VERSION, MAGIC, META_AUTHOR, META_CREATED_BY,
+    META_DESCRIPTION, META_FEATURES, META_NAME, META_SECTION_NAMES, META_SOURCE_HASH,
+};
+
 pub fn read_lib(bytes: &[u8]) -> Option<SerializeMap> {
-    let decompressed = inflate_bytes_zlib(bytes).ok()?;
-    let mut bytes = &decompressed[..];
+    read_library(bytes).ok().map(|lib| lib.map)
+}
+
+/// Read a library file of any format version up to [`FORMAT_VERSION`].
+pub fn read_library(bytes: &[u8]) -> Result<Library> {
+    let Some(mut rest) = bytes.strip_prefix(MAGIC) else {
+        // 第1版没有文件头，整个文件就是压缩的段落
+        let map = read_body(bytes)?;
+        return Ok(Library {
+            map,
+            meta: Metadata::default(),
+            version: 1,
+        });
+    };
+
+    let damaged = || anyhow!("the header of the library file is damaged");
+    let version = get_varint(&mut rest).ok_or_else(damaged)?;
+    if version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "the library file is of format version {version}, \
+            but only versions up to {FORMAT_VERSION} can be read, try a newer version of this program"
+        ));
+    }
+    let len = get_varint(&mut rest).ok_or_else(damaged)? as usize;
+    if len > rest.len() {
+        return Err(damaged());
+    }
+    let (header, body) = rest.split_at(len);
+    let meta = read_meta(header).ok_or_else(damaged)?;
+
+    let map = read_body(body)?;
+    if !meta.section_names.is_empty() && meta.section_names.len() != map.len() {
+        return Err(damaged());
+    }
+    Ok(Library { map, meta, version })
+}
+
+fn read_meta(mut header: &[u8]) -> Option<Metadata> {
+    let mut meta = Metadata::default();
+    while !header.is_empty() {
+        let tag = get_varint(&mut header)?;
+        let len = get_varint(&mut header)? as usize;
+        if len > header.len() {
+            return None;
+        }
+        let (mut field, rest) = header.split_at(len);
+        header = rest;
+
+        let text = |field: &[u8]| Some(str::from_utf8(field).ok()?.to_owned());
+        // 不认识的字段直接跳过
+        match tag {
+            META_NAME => meta.name = text(field),
+            META_DESCRIPTION => meta.description = text(field),
+            META_AUTHOR => meta.author = text(field),
+            META_CREATED_BY => meta.created_by = text(field),
+            META_SOURCE_HASH => meta.source_hash = Some(u64::from_le_bytes(field.try_into().ok()?)),
+            META_SECTION_NAMES => {
+                for _ in 0..get_varint(&mut field)? {
+                    meta.section_names.push(get_text(&mut field)?.to_string());
+                }
+            }
+            META_FEATURES => {
+                let features = get_varint(&mut field)?;
+                meta.compression = features & FEATURE_COMPRESSION != 0;
+                meta.fingerprint = features & FEATURE_FINGERPRINT != 0;
+            }
+            _ => {}
+        }
+    }
+    Some(meta)
+}
+
+fn read_body(bytes: &[u8]) -> Result<SerializeMap> {
+    let decompressed = inflate_bytes_zlib(bytes)
+        .map_err(|err| anyhow!("the library file is not a library, or is damaged: {err}"))?;
+    parse_body(&decompressed).ok_or_else(|| anyhow!("the sections in the library file are damaged"))
+}
 
+fn parse_body(mut bytes: &[u8]) -> Option<SerializeMap> {
     let mut sections = Vec::new();
     for _ in 0..get_varint(&mut bytes)? {
         let mut rules = Vec::new();
@@ -28,36 +111,96 @@ pub fn read_lib(bytes: &[u8]) -> Option<SerializeMap> {
         sections.push(Section {
             encoder: rules,
             decoder: table,
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
         });
     }
+
+    while !bytes.is_empty() {
+        let tag = get_varint(&mut bytes)?;
+        let len = get_varint(&mut bytes)? as usize;
+        if len > bytes.len() {
+            return None;
+        }
+        let (mut block, rest) = bytes.split_at(len);
+        bytes = rest;
+
+        // 不认识的扩展块直接跳过
+        match tag {
+            EXT_SECTION_FLAGS => {
+                for sec in &mut sections {
+                    let flags = get_varint(&mut block)?;
+                    sec.ignore_case = flags & FLAG_IGNORE_CASE != 0;
+                }
+            }
+            EXT_RULE_TAGS => {
+                for sec in &mut sections {
+                    for _ in 0..get_varint(&mut block)? {
+                        let mut tags = Vec::new();
+                        for _ in 0..get_varint(&mut block)? {
+                            tags.push(get_text(&mut block)?);
+                        }
+                        sec.tags.push(tags);
+                    }
+                }
+            }
+            EXT_RULE_WEIGHTS => {
+                for sec in &mut sections {
+                    for _ in 0..get_varint(&mut block)? {
+                        sec.weights.push(get_varint(&mut block)?);
+                    }
+                    if !sec.weights.is_empty() && sec.weights.len() != sec.encoder.len()
+                        || sec.weights.contains(&0)
+                    {
+                        return None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
     Some(sections)
 }
 
 fn get_seg(data: &mut &[u8]) -> Option<Seg> {
     match data.get_u8() {
-        0 => {
-            let txt_len = get_varint(data)? as usize;
-            if txt_len > data.len() {
-                return None;
-            }
-            let (text, rest) = data.split_at(txt_len);
-            let text = str::from_utf8(text).ok()?;
-            *data = rest;
-            Some(Seg::Text(ShareStr::new(text)))
-        }
+        0 => Some(Seg::Text(get_text(data)?)),
         1 => {
             let id = get_varint(data)?;
             Some(Seg::Use(id))
         }
+        2 => Some(Seg::Generate(get_text(data)?)),
+        3 => Some(Seg::Recall(get_varint(data)?)),
+        4 => {
+            let seg = get_varint(data)?;
+            let mut values = Vec::new();
+            for _ in 0..get_varint(data)? {
+                values.push(get_text(data)?);
+            }
+            Some(Seg::Attr { seg, values })
+        }
         _ => None,
     }
 }
 
+fn get_text(data: &mut &[u8]) -> Option<ShareStr> {
+    let txt_len = get_varint(data)? as usize;
+    if txt_len > data.len() {
+        return None;
+    }
+    let (text, rest) = data.split_at(txt_len);
+    let text = str::from_utf8(text).ok()?;
+    *data = rest;
+    Some(ShareStr::new(text))
+}
+
 fn get_layer(data: &mut &[u8]) -> Option<Layer> {
     match data.get_u8() {
         0 => Some(Layer::Certain(get_varint(data)?)),
         1 => {
-            let mut branch = HashMap::new();
+            let mut branch = BTreeMap::new();
             let len = get_varint(data)?;
             for _ in 0..len {
                 let ch = char::from_u32(get_varint(data)?)?;
@@ -68,11 +211,24 @@ fn get_layer(data: &mut &[u8]) -> Option<Layer> {
             }
             Some(Layer::Branch(branch))
         }
+        2 => Some(Layer::Search),
+        3 => {
+            let section = get_varint(data)?;
+            let next = get_layer(data)?;
+            Some(Layer::Through {
+                section,
+                next: next.into(),
+            })
+        }
+        4 => Some(Layer::Folded(get_layer(data)?.into())),
         _ => None,
     }
 }
 
 pub(super) fn get_varint(data: &mut &[u8]) -> Option<u32> {
+    if data.is_empty() {
+        return None;
+    }
     let header = data.get_u8();
     let used_bytes = ((header & 0b1100_0000) >> 6) as usize + 1;
 
@@ -81,5 +237,5 @@ pub(super) fn get_varint(data: &mut &[u8]) -> Option<u32> {
     data.read_exact(&mut buffer[1..used_bytes]).ok()?;
 
     let num = u32::from_be_bytes(buffer);
-    Some(num >> (4 - used_bytes) * 8)
+    Some(num >> ((4 - used_bytes) * 8))
 }
@@ -3,7 +3,72 @@ use deflate::deflate_bytes_zlib;
 
 use crate::syntax::{Layer, Section, Seg};
 
-pub fn save_lib(secs: &Vec<Section>) -> Vec<u8> {
+use super::{
+    Metadata, EXT_RULE_TAGS, EXT_RULE_WEIGHTS, EXT_SECTION_FLAGS, FEATURE_COMPRESSION,
+    FEATURE_FINGERPRINT, FLAG_IGNORE_CASE, FORMAT_VERSION, MAGIC, META_AUTHOR, META_CREATED_BY,
+    META_DESCRIPTION, META_FEATURES, META_NAME, META_SECTION_NAMES, META_SOURCE_HASH,
+};
+
+pub fn save_lib(secs: &[Section]) -> Vec<u8> {
+    save_library(secs, &Metadata::default())
+}
+
+/// Write a library file of the current format version: the magic, the version, the
+/// metadata, then the sections as a zlib stream.
+pub fn save_library(secs: &[Section], meta: &Metadata) -> Vec<u8> {
+    // 元数据不压缩，不用解开整个库也能读到
+    let mut header = Vec::new();
+    let texts = [
+        (META_NAME, &meta.name),
+        (META_DESCRIPTION, &meta.description),
+        (META_AUTHOR, &meta.author),
+        (META_CREATED_BY, &meta.created_by),
+    ];
+    for (tag, text) in texts {
+        if let Some(text) = text {
+            put_ext(&mut header, tag, text.as_bytes());
+        }
+    }
+    if let Some(hash) = meta.source_hash {
+        put_ext(&mut header, META_SOURCE_HASH, &hash.to_le_bytes());
+    }
+    if !meta.section_names.is_empty() {
+        let mut block = Vec::new();
+        put_varint(&mut block, meta.section_names.len() as _);
+        for name in &meta.section_names {
+            put_text(&mut block, name);
+        }
+        put_ext(&mut header, META_SECTION_NAMES, &block);
+    }
+    let features = [
+        (meta.compression, FEATURE_COMPRESSION),
+        (meta.fingerprint, FEATURE_FINGERPRINT),
+    ]
+    .into_iter()
+    .filter(|&(on, _)| on)
+    .fold(0, |features, (_, flag)| features | flag);
+    if features != 0 {
+        let mut block = Vec::new();
+        put_varint(&mut block, features);
+        put_ext(&mut header, META_FEATURES, &block);
+    }
+
+    let mut data = MAGIC.to_vec();
+    put_varint(&mut data, FORMAT_VERSION);
+    put_varint(&mut data, header.len() as _);
+    data.put(&header[..]);
+    data.put(&save_body(secs)[..]);
+    data
+}
+
+/// The sections as a zlib stream, which is all a file of version 1 has.
+pub(super) fn save_body(secs: &[Section]) -> Vec<u8> {
+    deflate_bytes_zlib(&put_body(secs))
+}
+
+/// The sections before compression. Every map is written the same way every time,
+/// tables in the order of their characters, so this is what the content hash is of.
+pub(super) fn put_body(secs: &[Section]) -> Vec<u8> {
     let mut data = Vec::new();
 
     put_varint(&mut data, secs.len() as _);
@@ -19,23 +84,87 @@ pub fn save_lib(secs: &Vec<Section>) -> Vec<u8> {
         put_layer(&mut data, &sec.decoder);
     }
 
-    deflate_bytes_zlib(&data)
+    // 扩展块跟在旧格式后面，用不到时不写，旧版本读到这里就停了
+    let flags: Vec<u32> = secs
+        .iter()
+        .map(|sec| if sec.ignore_case { FLAG_IGNORE_CASE } else { 0 })
+        .collect();
+    if flags.iter().any(|&f| f != 0) {
+        let mut block = Vec::new();
+        for f in flags {
+            put_varint(&mut block, f);
+        }
+        put_ext(&mut data, EXT_SECTION_FLAGS, &block);
+    }
+
+    if secs.iter().any(|sec| !sec.tags.is_empty()) {
+        let mut block = Vec::new();
+        for sec in secs {
+            put_varint(&mut block, sec.tags.len() as _);
+            for tags in &sec.tags {
+                put_varint(&mut block, tags.len() as _);
+                for tag in tags {
+                    put_text(&mut block, tag);
+                }
+            }
+        }
+        put_ext(&mut data, EXT_RULE_TAGS, &block);
+    }
+
+    if secs.iter().any(|sec| !sec.weights.is_empty()) {
+        let mut block = Vec::new();
+        for sec in secs {
+            put_varint(&mut block, sec.weights.len() as _);
+            for &weight in &sec.weights {
+                put_varint(&mut block, weight);
+            }
+        }
+        put_ext(&mut data, EXT_RULE_WEIGHTS, &block);
+    }
+
+    data
+}
+
+fn put_ext(data: &mut Vec<u8>, tag: u32, block: &[u8]) {
+    put_varint(data, tag);
+    put_varint(data, block.len() as _);
+    data.put(block);
 }
 
 fn put_seg(data: &mut Vec<u8>, seg: &Seg) {
     match seg {
         Seg::Text(txt) => {
             data.put_u8(0);
-            put_varint(data, txt.len() as _);
-            data.put(txt.as_bytes());
+            put_text(data, txt);
         }
         &Seg::Use(u) => {
             data.put_u8(1);
             put_varint(data, u);
         }
+        Seg::Generate(name) => {
+            data.put_u8(2);
+            put_text(data, name);
+        }
+        &Seg::Recall(seg) => {
+            data.put_u8(3);
+            put_varint(data, seg);
+        }
+        Seg::Attr { seg, values } => {
+            data.put_u8(4);
+            put_varint(data, *seg);
+            put_varint(data, values.len() as _);
+            for value in values {
+                put_text(data, value);
+            }
+        }
     }
 }
 
+fn put_text(data: &mut Vec<u8>, txt: &str) {
+    put_varint(data, txt.len() as _);
+    data.put(txt.as_bytes());
+}
+
 fn put_layer(data: &mut Vec<u8>, layer: &Layer) {
     match layer {
         &Layer::Certain(value) => {
@@ -50,6 +179,16 @@ fn put_layer(data: &mut Vec<u8>, layer: &Layer) {
                 put_layer(data, value);
             }
         }
+        Layer::Search => data.put_u8(2),
+        Layer::Through { section, next } => {
+            data.put_u8(3);
+            put_varint(data, *section);
+            put_layer(data, next);
+        }
+        Layer::Folded(inner) => {
+            data.put_u8(4);
+            put_layer(data, inner);
+        }
     }
 }
 
@@ -0,0 +1,164 @@
+//! Texts that say which library wrote them: the fingerprint of the library is
+//! encoded before the data, so decoding with another library is reported as such
+//! instead of giving garbage or a parse error.
+
+use std::fmt::{self, Display};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    decoder::{self, decode_with},
+    encoder::encode_with,
+    file::content_hash,
+    generator::GeneratorTable,
+    syntax::SerializeMap,
+};
+
+/// Short hash of a library, written before the data.
+pub fn fingerprint(map: &SerializeMap) -> u16 {
+    let hash = content_hash(map);
+    (0..4).fold(0, |id, i| id ^ (hash >> (i * 16)) as u16)
+}
+
+/// Error of decoding a text written by another library, told by the fingerprint the
+/// text starts with. Other errors of decoding do not mean the library is wrong.
+#[derive(Debug)]
+pub struct WrongLibrary {
+    /// Fingerprint the text starts with as read by the library decoding it. Another
+    /// library reads the bits differently, so this is not the fingerprint of the
+    /// library that wrote the text, which [`identify`] can find.
+    pub found: u16,
+    /// Fingerprint of the library decoding it.
+    pub expected: u16,
+}
+
+impl Display for WrongLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "this text was not produced by the loaded library, \
+            it starts with fingerprint {:04x} instead of {:04x}",
+            self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for WrongLibrary {}
+
+pub fn encode_fingerprinted(
+    map: &SerializeMap,
+    generators: &GeneratorTable,
+    input: &[u8],
+) -> Result<String> {
+    let mut framed = fingerprint(map).to_le_bytes().to_vec();
+    framed.extend_from_slice(input);
+    encode_with(map, generators, &framed)
+}
+
+pub fn decode_fingerprinted(
+    map: &SerializeMap,
+    generators: &GeneratorTable,
+    text: &str,
+) -> Result<Vec<u8>> {
+    let expected = fingerprint(map);
+    let decoded = decode_with(map, generators, text);
+    // 用错了库时读出来的结尾对不上，开头的指纹还是能从读到的部分里找到
+    let head = match &decoded {
+        Ok(bytes) if bytes.len() >= 2 => Some([bytes[0], bytes[1]]),
+        _ => decoder::decode_prefix(map, generators, text)
+            .get(..2)
+            .map(|head| [head[0], head[1]]),
+    };
+    // 别的库写的文本读出来的比特也不一样，只能知道不是这个库写的
+    if let Some(found) = head.map(u16::from_le_bytes) {
+        if found != expected {
+            return Err(WrongLibrary { found, expected }.into());
+        }
+    }
+
+    let decoded = decoded?;
+    if decoded.len() < 2 {
+        return Err(anyhow!(
+            "this text carries no library fingerprint, it was written without one"
+        ));
+    }
+    Ok(decoded[2..].to_vec())
+}
+
+/// Index of the first of `maps` that wrote `text`, by the fingerprint it starts with.
+pub fn identify<'a>(
+    maps: impl IntoIterator<Item = &'a SerializeMap>,
+    generators: &GeneratorTable,
+    text: &str,
+) -> Option<usize> {
+    maps.into_iter()
+        .position(|map| decode_fingerprinted(map, generators, text).is_ok())
+}
+
+#[test]
+fn test_fingerprint() {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    let library = |words: &[&str]| {
+        vec![Section {
+            encoder: words
+                .iter()
+                .map(|&w| vec![Seg::Text(ShareStr::new(w))])
+                .collect(),
+            decoder: Layer::Branch(
+                words
+                    .iter()
+                    .zip(0..)
+                    .map(|(w, i)| (w.chars().next().unwrap(), Layer::Certain(i)))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        }]
+    };
+    let old = library(&["甲", "乙"]);
+    let new = library(&["甲", "乙", "丙"]);
+    let generators = GeneratorTable::new();
+
+    let text = encode_fingerprinted(&old, &generators, b"fg2").unwrap();
+    assert_eq!(
+        decode_fingerprinted(&old, &generators, &text).unwrap(),
+        b"fg2"
+    );
+
+    // 新库也能读出这段文本，但读出来的不对
+    assert!(decode_with(&new, &generators, &text).is_ok());
+    let wrong_library = |text: &str| {
+        let err = decode_fingerprinted(&new, &generators, text).unwrap_err();
+        let wrong = err.downcast_ref::<WrongLibrary>().unwrap();
+        assert_eq!(wrong.expected, fingerprint(&new));
+        let expected = format!(
+            "not produced by the loaded library, it starts with fingerprint {:04x} instead of {:04x}",
+            wrong.found, wrong.expected
+        );
+        assert!(err.to_string().contains(&expected), "{err}");
+    };
+    wrong_library(&text);
+    assert_eq!(identify([&new, &old], &generators, &text), Some(1));
+
+    // 读到一半读不下去，开头的指纹还是能读出来
+    wrong_library(&format!("{text}丁"));
+
+    // 库对了但数据坏了，不能当成用错了库
+    let lib = crate::file::Library::new(
+        library(&["甲", "乙"]),
+        crate::file::Metadata {
+            fingerprint: true,
+            ..Default::default()
+        },
+    );
+    let text = encode_fingerprinted(&lib.map, &generators, b"\xff\xfe").unwrap();
+    let err = crate::decode_library(&lib, &text).unwrap_err();
+    assert!(!err.is::<WrongLibrary>(), "{err}");
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, rc::Rc};
+
+/// A section whose words are computed in Rust instead of listed in a library file.
+///
+/// A generator offers `count()` values. The encoder picks one of them with the
+/// same bit splitting it uses for the rules of a native section, and the decoder
+/// must be able to tell which value produced a piece of text.
+pub trait Generator {
+    /// Number of distinct values, must be at least 1.
+    /// Libraries using a generator without any values fail to compile and to encode.
+    fn count(&self) -> u32;
+
+    /// Render the value, which is always less than `count()`.
+    fn encode(&self, value: u32) -> String;
+
+    /// Recognize a value at the start of `input`, returning it with the count of bytes consumed.
+    /// The input is in Unicode NFC form.
+    fn decode(&self, input: &str) -> Option<(u32, usize)>;
+}
+
+#[derive(Clone, Default)]
+pub struct GeneratorTable {
+    table: HashMap<String, Rc<dyn Generator>>,
+}
+
+impl GeneratorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<G>(&mut self, name: impl Into<String>, generator: G)
+    where
+        G: Generator + 'static,
+    {
+        self.table.insert(name.into(), Rc::new(generator));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Generator> {
+        self.table.get(name).map(|g| &**g)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.table.contains_key(name)
+    }
+}
+
+#[test]
+fn test_generator_round_trip() {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    struct Minute;
+
+    impl Generator for Minute {
+        fn count(&self) -> u32 {
+            60
+        }
+
+        fn encode(&self, value: u32) -> String {
+            format!("{value:02}分")
+        }
+
+        fn decode(&self, input: &str) -> Option<(u32, usize)> {
+            let value = input.get(..2)?.parse().ok()?;
+            input[2..]
+                .starts_with('分')
+                .then_some((value, 2 + '分'.len_utf8()))
+        }
+    }
+
+    let map = vec![Section {
+        encoder: vec![vec![
+            Seg::Text(ShareStr::new("第")),
+            Seg::Generate(ShareStr::new("分钟")),
+        ]],
+        decoder: Layer::Branch(BTreeMap::from([('第', Layer::Certain(0))])),
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    }];
+
+    let mut generators = GeneratorTable::new();
+    generators.register("分钟", Minute);
+
+    let input = "发电".as_bytes();
+    let encoded = crate::encode_with(&map, &generators, input).unwrap();
+    assert!(encoded.starts_with('第'));
+    assert_eq!(
+        crate::decode_with(&map, &generators, &encoded).unwrap(),
+        input
+    );
+    assert!(crate::encode(&map, input).is_err());
+}
+
+#[test]
+fn test_empty_generator() {
+    use crate::{
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    struct Nothing;
+
+    impl Generator for Nothing {
+        fn count(&self) -> u32 {
+            0
+        }
+
+        fn encode(&self, _: u32) -> String {
+            unreachable!()
+        }
+
+        fn decode(&self, _: &str) -> Option<(u32, usize)> {
+            None
+        }
+    }
+
+    let map = vec![Section {
+        encoder: vec![vec![Seg::Generate(ShareStr::new("无"))]],
+        decoder: Layer::Certain(0),
+        ignore_case: false,
+        tags: Vec::new(),
+        weights: Vec::new(),
+    }];
+
+    let mut generators = GeneratorTable::new();
+    generators.register("无", Nothing);
+
+    let err = crate::encode_with(&map, &generators, b"fg2").unwrap_err();
+    assert!(err.to_string().contains("has no values"), "{err}");
+
+    #[cfg(feature = "compile")]
+    {
+        use crate::syntax::{compile_from, CompileOptions, MemorySources};
+
+        let sources = MemorySources::from_iter([("lib/entry.txt", "[entry]\n有{无}\n")]);
+        let options = CompileOptions {
+            generators,
+            ..CompileOptions::default()
+        };
+        let err = compile_from(&sources, "lib", &options).err().unwrap();
+        assert!(err.to_string().contains("has no values"), "{err}");
+    }
+}
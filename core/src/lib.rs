@@ -1,25 +1,127 @@
 use anyhow::Result;
+use file::Library;
+use generator::GeneratorTable;
 use syntax::SerializeMap;
 
-pub use self::{decoder::decode, encoder::encode};
+pub use self::{
+    decoder::{decode, decode_with},
+    encoder::{encode, encode_with},
+};
 
 pub mod decoder;
 pub mod encoder;
 pub mod file;
+pub mod fingerprint;
+mod fnv;
+pub mod generator;
 pub mod share_str;
 pub mod syntax;
 mod varint;
 
 pub fn decode_mode(map: &SerializeMap, encoded_text: &str) -> Result<String> {
-    let decoded = decode(map, encoded_text)?;
-    #[cfg(feature = "compression")]
-    let decoded = inflate::inflate_bytes(&decoded).map_err(anyhow::Error::msg)?;
-    Ok(String::from_utf8(decoded)?)
+    bytes_to_text(decode(map, encoded_text)?, cfg!(feature = "compression"))
 }
 
 pub fn encode_mode(map: &SerializeMap, decode_text: &str) -> Result<String> {
-    let origin = decode_text.as_bytes();
-    #[cfg(feature = "compression")]
-    let origin = deflate::deflate_bytes(origin);
-    Ok(encode(&map, &origin))
+    encode(
+        map,
+        &text_to_bytes(decode_text, cfg!(feature = "compression")),
+    )
+}
+
+/// Like [`decode_mode`], checking the fingerprint first if the library writes one.
+pub fn decode_library(lib: &Library, encoded_text: &str) -> Result<String> {
+    let decoded = if lib.meta.fingerprint {
+        fingerprint::decode_fingerprinted(&lib.map, &GeneratorTable::new(), encoded_text)?
+    } else {
+        decode(&lib.map, encoded_text)?
+    };
+    bytes_to_text(decoded, compressed(lib))
+}
+
+/// Like [`encode_mode`], starting with the fingerprint if the library writes one.
+pub fn encode_library(lib: &Library, decode_text: &str) -> Result<String> {
+    let origin = text_to_bytes(decode_text, compressed(lib));
+    if lib.meta.fingerprint {
+        fingerprint::encode_fingerprinted(&lib.map, &GeneratorTable::new(), &origin)
+    } else {
+        encode(&lib.map, &origin)
+    }
+}
+
+/// Whether texts of `lib` are deflated. Files of version 1 do not say, they are taken
+/// to be deflated if this crate is built with compression.
+fn compressed(lib: &Library) -> bool {
+    if lib.version >= 2 {
+        lib.meta.compression
+    } else {
+        cfg!(feature = "compression")
+    }
+}
+
+fn bytes_to_text(decoded: Vec<u8>, compressed: bool) -> Result<String> {
+    let decoded = if compressed {
+        inflate::inflate_bytes(&decoded).map_err(anyhow::Error::msg)?
+    } else {
+        decoded
+    };
+    Ok(String::from_utf8(decoded)?)
+}
+
+fn text_to_bytes(text: &str, compressed: bool) -> Vec<u8> {
+    if compressed {
+        deflate::deflate_bytes(text.as_bytes())
+    } else {
+        text.as_bytes().to_vec()
+    }
+}
+
+#[test]
+fn test_compression() {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        file::Metadata,
+        share_str::ShareStr,
+        syntax::{Layer, Section, Seg},
+    };
+
+    let map = || {
+        vec![Section {
+            encoder: vec![
+                vec![Seg::Text(ShareStr::new("甲"))],
+                vec![Seg::Text(ShareStr::new("乙"))],
+            ],
+            decoder: Layer::Branch(BTreeMap::from([
+                ('甲', Layer::Certain(0)),
+                ('乙', Layer::Certain(1)),
+            ])),
+            ignore_case: false,
+            tags: Vec::new(),
+            weights: Vec::new(),
+        }]
+    };
+    let text = "你好你好你好你好";
+
+    // 第2版的文件自己说了压没压缩，和编译时的特性无关
+    for compression in [false, true] {
+        let meta = Metadata {
+            compression,
+            ..Metadata::default()
+        };
+        let lib = Library::new(map(), meta);
+        let encoded = encode_library(&lib, text).unwrap();
+        assert_eq!(decode_library(&lib, &encoded).unwrap(), text);
+
+        let data = decode(&lib.map, &encoded).unwrap();
+        match compression {
+            true => assert_eq!(inflate::inflate_bytes(&data).unwrap(), text.as_bytes()),
+            false => assert_eq!(data, text.as_bytes()),
+        }
+    }
+
+    let mut legacy = Library::new(map(), Metadata::default());
+    legacy.version = 1;
+    let encoded = encode_library(&legacy, text).unwrap();
+    assert_eq!(decode_mode(&legacy.map, &encoded).unwrap(), text);
 }
@@ -44,6 +44,16 @@ impl ShareStr {
         &self.data[self.range.clone()]
     }
 
+    /// Byte offset of this string in the one it is shared from.
+    pub fn offset(&self) -> usize {
+        self.range.start
+    }
+
+    /// The whole string this one is shared from.
+    pub fn source(&self) -> &str {
+        &self.data
+    }
+
     pub fn clone_range<T>(&self, range: T) -> Self
     where
         T: RangeBounds<usize>,
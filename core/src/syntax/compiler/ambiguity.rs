@@ -0,0 +1,546 @@
+//! Sections whose rules cannot be told apart by looking ahead are decoded by searching,
+//! which only works if no text can be read in two ways. To prove it, two derivations are
+//! walked over the same text side by side, starting where such a section is used and
+//! parting at a choice inside it. If they meet again, or both finish a text of `entry`,
+//! the text read so far has two meanings.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{generator::GeneratorTable, syntax::fold_case};
+
+use super::{
+    decodability::GENERATOR_SAMPLES,
+    diagnostic::{error_at, Span},
+    link::{LinkedSection, LinkedSeg},
+    searcher::{display_rule, Trie},
+    Budget,
+};
+
+#[derive(Clone)]
+struct SecRef(Rc<LinkedSection>);
+
+impl PartialEq for SecRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecRef {}
+
+impl Hash for SecRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state)
+    }
+}
+
+/// Choices and texts of the segments read so far, for rules with derived segments.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Records {
+    NotNeeded,
+    Known(Vec<(u32, Rc<str>)>),
+    /// Read before the walk started.
+    Unknown,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    Rule {
+        sec: SecRef,
+        rule: u32,
+        seg: u32,
+        records: Records,
+        /// Text read inside the rule, kept when the rule using it recalls it.
+        capture: Option<String>,
+    },
+    Literal {
+        text: Rc<str>,
+        offset: u32,
+        ignore_case: bool,
+    },
+}
+
+/// Frames of a derivation, innermost last. Empty at the end of a text of `entry`.
+type Walk = Vec<Frame>;
+
+/// Where two derivations part.
+struct Parting {
+    left: String,
+    right: String,
+    /// The section whose rules or values differ.
+    origin: String,
+    span: Option<Span>,
+}
+
+struct Pair {
+    left: Walk,
+    right: Walk,
+    text: String,
+    parting: Option<Rc<Parting>>,
+}
+
+struct Checker<'a> {
+    entry: &'a Rc<LinkedSection>,
+    generators: &'a GeneratorTable,
+    reaches_search: HashMap<*const LinkedSection, bool>,
+    budget: Budget<'a>,
+    states: usize,
+}
+
+/// Prove that no text is read in two ways by the sections the decoder searches.
+pub fn check(entry: &Rc<LinkedSection>, generators: &GeneratorTable, budget: Budget) -> Result<()> {
+    let mut checker = Checker {
+        entry,
+        generators,
+        reaches_search: HashMap::new(),
+        budget,
+        states: 0,
+    };
+    if !checker.reaches_search(entry) {
+        return Ok(());
+    }
+
+    let mut contexts = Vec::new();
+    checker.contexts(entry, &mut Vec::new(), &mut contexts);
+    for (context, sec) in contexts {
+        checker.walk(context, &sec)?;
+    }
+    Ok(())
+}
+
+impl Checker<'_> {
+    fn reaches_search(&mut self, sec: &Rc<LinkedSection>) -> bool {
+        if let Some(&known) = self.reaches_search.get(&Rc::as_ptr(sec)) {
+            return known;
+        }
+
+        let mut found = matches!(*sec.search, Trie::Search);
+        for seg in sec.rules.iter().flat_map(|rule| &rule.segs) {
+            if found {
+                break;
+            }
+            if let LinkedSeg::Use(inner) = seg {
+                found = self.reaches_search(inner);
+            }
+        }
+
+        self.reaches_search.insert(Rc::as_ptr(sec), found);
+        found
+    }
+
+    /// Every chain of rules from `entry` to a searched section.
+    fn contexts(
+        &mut self,
+        sec: &Rc<LinkedSection>,
+        stack: &mut Walk,
+        out: &mut Vec<(Walk, Rc<LinkedSection>)>,
+    ) {
+        // 被搜索的段落里面的选择都会在走的时候分开，不用再往里找
+        if matches!(*sec.search, Trie::Search) {
+            out.push((stack.clone(), sec.clone()));
+            return;
+        }
+
+        for (rule, r) in sec.rules.iter().zip(0..) {
+            for (seg, k) in rule.segs.iter().zip(0..) {
+                let LinkedSeg::Use(inner) = seg else {
+                    continue;
+                };
+                if !self.reaches_search(inner) {
+                    continue;
+                }
+
+                stack.push(Frame::Rule {
+                    sec: SecRef(sec.clone()),
+                    rule: r,
+                    seg: k,
+                    records: if needs_records(&rule.segs) {
+                        Records::Unknown
+                    } else {
+                        Records::NotNeeded
+                    },
+                    capture: None,
+                });
+                self.contexts(inner, stack, out);
+                stack.pop();
+            }
+        }
+    }
+
+    fn walk(&mut self, context: Walk, sec: &Rc<LinkedSection>) -> Result<()> {
+        let depth = context.len() + 1;
+        let mut queue = VecDeque::from([Pair {
+            left: context.clone(),
+            right: context,
+            text: String::new(),
+            parting: None,
+        }]);
+        let mut visited = HashSet::new();
+
+        while let Some(pair) = queue.pop_front() {
+            self.states += 1;
+            if self.states.is_multiple_of(4096) {
+                self.budget.check_time(|| {
+                    format!(
+                        "proving that texts using section [{}] have one meaning",
+                        sec.info
+                    )
+                })?;
+            }
+            if self.states > self.budget.limits.max_search_states {
+                return Err(error_at(
+                    sec.info.span.as_ref(),
+                    anyhow!(
+                        "section [{}] is too complex to prove that texts using it have only \
+                        one meaning, make sure no rule is the beginning of another",
+                        sec.info
+                    ),
+                ));
+            }
+
+            let started = !pair.text.is_empty();
+            let mut lefts = self.settle(pair.left, started)?;
+            let mut rights = self.settle(pair.right, started)?;
+
+            if let Some(parting) = &pair.parting {
+                let right_set: HashSet<&Walk> = rights.iter().collect();
+                let met = lefts.iter().any(|left| right_set.contains(left));
+                let both_end = lefts.iter().any(Vec::is_empty) && rights.iter().any(Vec::is_empty);
+                if met || both_end {
+                    return Err(error_at(
+                        parting.span.as_ref().or(sec.info.span.as_ref()),
+                        anyhow!(
+                            "`{}{}` can be read in two ways, with `{}` or with `{}` of {}",
+                            pair.text,
+                            if met && !both_end { "…" } else { "" },
+                            parting.left,
+                            parting.right,
+                            parting.origin
+                        ),
+                    ));
+                }
+            } else {
+                // 两边一起走出了这个段落，之后的分歧由别的段落负责
+                lefts.retain(|walk| walk.len() >= depth);
+                rights.retain(|walk| walk.len() >= depth);
+            }
+
+            let lefts = self.restart_ended(lefts)?;
+            let rights = self.restart_ended(rights)?;
+
+            let mut by_char: HashMap<char, Vec<&Walk>> = HashMap::new();
+            for right in &rights {
+                let (ch, _) = next_char(right);
+                by_char.entry(fold_case(ch)).or_default().push(right);
+            }
+
+            for left in &lefts {
+                let (ch, left_ignore_case) = next_char(left);
+                let Some(candidates) = by_char.get(&fold_case(ch)) else {
+                    continue;
+                };
+                for &right in candidates {
+                    let (right_ch, right_ignore_case) = next_char(right);
+                    if ch != right_ch && !left_ignore_case && !right_ignore_case {
+                        continue;
+                    }
+
+                    let parting = match &pair.parting {
+                        Some(parting) => Some(parting.clone()),
+                        None if left != right => Some(Rc::new(parting(left, right))),
+                        None => None,
+                    };
+                    let left = step(left);
+                    let right = step(right);
+                    if !visited.insert((left.clone(), right.clone(), parting.is_some())) {
+                        continue;
+                    }
+
+                    let mut text = pair.text.clone();
+                    text.push(ch);
+                    queue.push_back(Pair {
+                        left,
+                        right,
+                        text,
+                        parting,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start the next text of `entry` where one has ended.
+    fn restart_ended(&self, walks: Vec<Walk>) -> Result<Vec<Walk>> {
+        let mut ready = Vec::with_capacity(walks.len());
+        for walk in walks {
+            if walk.is_empty() {
+                ready.extend(self.settle(Vec::new(), false)?);
+            } else {
+                ready.push(walk);
+            }
+        }
+        Ok(ready)
+    }
+
+    /// Every way to continue `walk` until it is about to read a character or has
+    /// finished a text of `entry`. An empty `walk` that has not `started` begins one.
+    fn settle(&self, walk: Walk, started: bool) -> Result<Vec<Walk>> {
+        let mut out = Vec::new();
+        let mut pending = vec![walk];
+
+        if pending[0].is_empty() && !started {
+            pending = (0..self.entry.rules.len() as u32)
+                .map(|rule| vec![self.enter(self.entry, rule, false)])
+                .collect();
+        }
+
+        while let Some(mut walk) = pending.pop() {
+            let Some(top) = walk.last() else {
+                out.push(walk);
+                continue;
+            };
+
+            let (sec, rule, seg, records) = match top {
+                Frame::Literal { text, offset, .. } => {
+                    if *offset as usize == text.len() {
+                        walk.pop();
+                        pending.push(walk);
+                    } else {
+                        out.push(walk);
+                    }
+                    continue;
+                }
+                Frame::Rule {
+                    sec,
+                    rule,
+                    seg,
+                    records,
+                    ..
+                } => (sec.0.clone(), *rule, *seg, records.clone()),
+            };
+
+            let segs = &sec.rules[rule as usize].segs;
+            let Some(current) = segs.get(seg as usize) else {
+                let Some(Frame::Rule { capture, .. }) = walk.pop() else {
+                    unreachable!();
+                };
+                if !walk.is_empty() {
+                    advance(&mut walk, rule, capture.unwrap_or_default().into(), None);
+                }
+                pending.push(walk);
+                continue;
+            };
+
+            let derived = |index: u32| match &records {
+                Records::Known(records) => Ok(records[index as usize].clone()),
+                Records::NotNeeded => unreachable!("derived segments keep records"),
+                Records::Unknown => Err(error_at(
+                    sec.rules[rule as usize].span.as_ref(),
+                    anyhow!(
+                        "cannot prove that `{}` in section [{}] has only one meaning, \
+                        because it repeats a word read before a section that is decoded \
+                        by searching",
+                        display_rule(segs),
+                        sec.info
+                    ),
+                )),
+            };
+
+            let ignore_case = sec.info.ignore_case;
+            match current {
+                LinkedSeg::Text(t) => {
+                    advance(&mut walk, 0, t.as_str().into(), Some(ignore_case));
+                    pending.push(walk);
+                }
+                LinkedSeg::Use(inner) => {
+                    let capture = segs
+                        .iter()
+                        .any(|s| matches!(s, &LinkedSeg::Recall { seg: r, .. } if r == seg));
+                    for r in 0..inner.rules.len() as u32 {
+                        let mut walk = walk.clone();
+                        walk.push(self.enter(inner, r, capture));
+                        pending.push(walk);
+                    }
+                }
+                LinkedSeg::Generate(name) => {
+                    if let Some(generator) = self.generators.get(name) {
+                        for value in 0..generator.count().min(GENERATOR_SAMPLES) {
+                            let mut walk = walk.clone();
+                            let text = generator.encode(value).into();
+                            advance(&mut walk, value, text, Some(ignore_case));
+                            pending.push(walk);
+                        }
+                    }
+                }
+                &LinkedSeg::Recall { seg: index, .. } => {
+                    let (_, text) = derived(index)?;
+                    advance(&mut walk, 0, text, Some(ignore_case));
+                    pending.push(walk);
+                }
+                LinkedSeg::Attr {
+                    seg: index, values, ..
+                } => {
+                    let (choice, _) = derived(*index)?;
+                    let text = values[choice as usize].as_str().into();
+                    advance(&mut walk, 0, text, Some(ignore_case));
+                    pending.push(walk);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn enter(&self, sec: &Rc<LinkedSection>, rule: u32, capture: bool) -> Frame {
+        Frame::Rule {
+            sec: SecRef(sec.clone()),
+            rule,
+            seg: 0,
+            records: if needs_records(&sec.rules[rule as usize].segs) {
+                Records::Known(Vec::new())
+            } else {
+                Records::NotNeeded
+            },
+            capture: capture.then(String::new),
+        }
+    }
+}
+
+fn needs_records(segs: &[LinkedSeg]) -> bool {
+    segs.iter()
+        .any(|seg| matches!(seg, LinkedSeg::Recall { .. } | LinkedSeg::Attr { .. }))
+}
+
+/// Finish the current segment of the rule on top of `walk`, and read `text` next
+/// if `literal` says in which letter case.
+fn advance(walk: &mut Walk, choice: u32, text: Rc<str>, literal: Option<bool>) {
+    let Some(Frame::Rule { seg, records, .. }) = walk.last_mut() else {
+        unreachable!("segments belong to rules");
+    };
+    *seg += 1;
+    if let Records::Known(records) = records {
+        records.push((choice, text.clone()));
+    }
+
+    if let Some(ignore_case) = literal {
+        if !text.is_empty() {
+            walk.push(Frame::Literal {
+                text,
+                offset: 0,
+                ignore_case,
+            });
+        }
+    }
+}
+
+fn next_char(walk: &Walk) -> (char, bool) {
+    let Some(Frame::Literal {
+        text,
+        offset,
+        ignore_case,
+    }) = walk.last()
+    else {
+        unreachable!("settled walks are about to read");
+    };
+    (
+        text[*offset as usize..].chars().next().unwrap(),
+        *ignore_case,
+    )
+}
+
+fn step(walk: &Walk) -> Walk {
+    let mut walk = walk.clone();
+    let (ch, _) = next_char(&walk);
+    for frame in &mut walk {
+        match frame {
+            Frame::Rule {
+                capture: Some(capture),
+                ..
+            } => capture.push(ch),
+            Frame::Literal { offset, .. } => *offset += ch.len_utf8() as u32,
+            _ => {}
+        }
+    }
+    walk
+}
+
+fn parting(left: &Walk, right: &Walk) -> Parting {
+    let describe = |frame: Option<&Frame>| match frame {
+        Some(Frame::Rule { sec, rule, .. }) => {
+            let rule = &sec.0.rules[*rule as usize];
+            (display_rule(&rule.segs), rule.span.clone())
+        }
+        Some(Frame::Literal { text, .. }) => (text.to_string(), None),
+        None => (String::new(), None),
+    };
+
+    let index = left
+        .iter()
+        .zip(right)
+        .position(|(l, r)| l != r)
+        .unwrap_or(left.len().min(right.len()));
+    // 不同的是生成器的值时，说的是它所在的规则
+    let origin = match left.get(index) {
+        Some(Frame::Rule { sec, .. }) => format!("section [{}]", sec.0.info),
+        _ => match index.checked_sub(1).and_then(|i| left.get(i)) {
+            Some(Frame::Rule { sec, rule, .. }) => format!(
+                "`{}` in section [{}]",
+                display_rule(&sec.0.rules[*rule as usize].segs),
+                sec.0.info
+            ),
+            _ => "section [entry]".to_owned(),
+        },
+    };
+    let (left, span) = describe(left.get(index));
+    let (right, _) = describe(right.get(index));
+    Parting {
+        left,
+        right,
+        origin,
+        span,
+    }
+}
+
+#[test]
+fn test_two_ways() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let compile = |entry: &str| {
+        let sources: MemorySources = [("lib/entry.txt", entry)].into_iter().collect();
+        compile_from(&sources, "lib", &CompileOptions::default())
+    };
+
+    // 饼干饭只能读成饼+干饭，饼干汤只能读成饼干+汤
+    compile("[entry]\n{甲}{乙}\n[甲]\n饼\n饼干\n[乙]\n干饭\n汤\n").unwrap();
+    compile("[entry]\n{用户}买了{商品}\n[用户]\n小明\n小红\n[商品]\n苹果\n苹果派\n").unwrap();
+
+    let err = compile("[entry]\n{甲}{乙}\n[甲]\n饼\n饼干\n[乙]\n干饭\n饭\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.starts_with(
+            "`饼干饭` can be read in two ways, with `饼干` or with `饼` of section [`甲`"
+        ),
+        "{err}"
+    );
+
+    // 两种读法到下一句才合到一起
+    let err = compile("[entry]\n{用户}买了{商品}\n[用户]\n小明\n派小明\n[商品]\n苹果\n苹果派\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.starts_with(
+            "`苹果派小明…` can be read in two ways, with `苹果派` or with `苹果` of section [`商品`"
+        ),
+        "{err}"
+    );
+}
@@ -0,0 +1,146 @@
+use std::{path::Path, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::{share_str::ShareStr, syntax::SerializeMap};
+
+use super::{
+    link,
+    parse_tokens::{ExprRef, ExprRule, ExprSection, ExprSeg, SecInfo, SectionKind},
+    serialize, CompileOptions,
+};
+
+/// A piece of a rule added by [`LibraryBuilder`].
+#[derive(Clone, Debug)]
+pub enum RulePart {
+    Text(String),
+    /// A word chosen from the section of this name, like `{name}` in a library file.
+    Section(String),
+}
+
+/// Build a library in code instead of from library files. The section named `entry`
+/// is where encoding starts, and the rules are checked the same way as in a file.
+#[derive(Clone, Debug, Default)]
+pub struct LibraryBuilder {
+    sections: Vec<(String, Vec<Vec<RulePart>>)>,
+}
+
+impl LibraryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a rule to a section, creating the section if needed.
+    pub fn add_rule(
+        &mut self,
+        section: impl Into<String>,
+        parts: impl IntoIterator<Item = RulePart>,
+    ) -> &mut Self {
+        let section = section.into();
+        let parts = parts.into_iter().collect();
+        match self.sections.iter_mut().find(|(name, _)| *name == section) {
+            Some((_, rules)) => rules.push(parts),
+            None => self.sections.push((section, vec![parts])),
+        }
+        self
+    }
+
+    /// Append a rule that is just the text `word`.
+    pub fn add_word(&mut self, section: impl Into<String>, word: impl Into<String>) -> &mut Self {
+        self.add_rule(section, [RulePart::Text(word.into())])
+    }
+
+    pub fn build(&self) -> Result<SerializeMap> {
+        self.build_with(&CompileOptions::default())
+    }
+
+    pub fn build_with(&self, options: &CompileOptions) -> Result<SerializeMap> {
+        let file: Rc<Path> = Path::new("<builder>").into();
+        let nfc = |s: &str| ShareStr::new(&s.nfc().collect::<String>());
+
+        let mut sections = Vec::with_capacity(self.sections.len());
+        for (name, rules) in &self.sections {
+            let mut expr_rules = Vec::with_capacity(rules.len());
+            for parts in rules {
+                let segs: Vec<ExprSeg> = parts
+                    .iter()
+                    .filter(|part| !matches!(part, RulePart::Text(t) if t.is_empty()))
+                    .map(|part| match part {
+                        RulePart::Text(t) => ExprSeg::Quoted(nfc(t)),
+                        RulePart::Section(s) => ExprSeg::Use {
+                            target: ExprRef {
+                                name: nfc(s),
+                                args: Vec::new(),
+                                filter: Vec::new(),
+                            },
+                            capture: None,
+                        },
+                    })
+                    .collect();
+                if segs.is_empty() {
+                    return Err(anyhow!("a rule of section `{name}` is empty"));
+                }
+                let normalized = parts.iter().any(|part| {
+                    let (RulePart::Text(s) | RulePart::Section(s)) = part;
+                    !is_nfc(s)
+                });
+                expr_rules.push(ExprRule {
+                    segs,
+                    tags: Vec::new(),
+                    attrs: Vec::new(),
+                    weight: 1,
+                    span: None,
+                    normalized,
+                });
+            }
+
+            sections.push(ExprSection {
+                rules: expr_rules,
+                info: SecInfo {
+                    name: nfc(name),
+                    file: file.clone(),
+                    scope: Rc::from(""),
+                    private: false,
+                    ignore_case: false,
+                    span: None,
+                },
+                params: Vec::new(),
+                kind: SectionKind::Rules,
+                inherit_depth: 0,
+            });
+        }
+
+        let linked = link::link_secs(sections, options)?;
+        Ok(serialize::serialize(&linked))
+    }
+}
+
+#[test]
+fn test_library_builder() {
+    let mut builder = LibraryBuilder::new();
+    builder
+        .add_rule(
+            "entry",
+            [
+                RulePart::Section("用户".into()),
+                RulePart::Text("买了".into()),
+                RulePart::Section("商品".into()),
+            ],
+        )
+        .add_word("用户", "小明")
+        .add_word("用户", "小红");
+    for word in ["苹果", "香蕉", "橙子"] {
+        builder.add_word("商品", word);
+    }
+
+    let map = builder.build().unwrap();
+    let text = crate::encode(&map, b"hi").unwrap();
+    assert_eq!(crate::decode(&map, &text).unwrap(), b"hi");
+
+    // 有歧义的词和库文件一样报错
+    builder
+        .add_word("商品", "苹果派")
+        .add_word("用户", "派小明");
+    assert!(builder.build().is_err());
+}
@@ -0,0 +1,330 @@
+//! A section is prefix-free when `searcher::compile` manages to build its trie, and
+//! `ambiguity::check` proves that the other sections never make a text ambiguous, so
+//! texts of `entry` written one after another can only be read in one way.
+//! What remains to check is that the decoder reads the same text the encoder writes:
+//! Unicode normalization may merge characters where two words meet, and a generator
+//! may read its value further than it wrote it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Error, Result};
+use unicode_normalization::{
+    char::canonical_combining_class, is_nfc_quick, IsNormalized, UnicodeNormalization,
+};
+
+use crate::generator::GeneratorTable;
+
+use super::{
+    ambiguity,
+    diagnostic::{error_at, Diagnostics, Span},
+    link::{LinkedSection, LinkedSeg},
+    Budget,
+};
+
+/// Values of a generator tried where it meets other text, larger generators are only
+/// partly checked.
+pub(super) const GENERATOR_SAMPLES: u32 = 256;
+
+/// A word that starts or ends with some character, and where it comes from.
+type Example = (String, String);
+
+/// What the texts of a section or a segment look like at their ends.
+#[derive(Default)]
+struct Edges {
+    first: BTreeMap<char, Example>,
+    last: BTreeMap<char, Example>,
+    /// Generators that may write the end of the text.
+    last_generators: BTreeSet<String>,
+    /// Whether the text may be empty.
+    nullable: bool,
+}
+
+impl Edges {
+    fn word(word: &str, origin: &str) -> Self {
+        let mut edges = Edges::default();
+        edges.add_word(word, origin);
+        edges
+    }
+
+    fn add_word(&mut self, word: &str, origin: &str) {
+        let example = || (word.to_owned(), origin.to_owned());
+        match (word.chars().next(), word.chars().next_back()) {
+            (Some(first), Some(last)) => {
+                self.first.entry(first).or_insert_with(example);
+                self.last.entry(last).or_insert_with(example);
+            }
+            _ => self.nullable = true,
+        }
+    }
+
+    fn union(&mut self, other: &Edges) {
+        for (&ch, example) in &other.first {
+            self.first.entry(ch).or_insert_with(|| example.clone());
+        }
+        for (&ch, example) in &other.last {
+            self.last.entry(ch).or_insert_with(|| example.clone());
+        }
+        self.last_generators
+            .extend(other.last_generators.iter().cloned());
+        self.nullable |= other.nullable;
+    }
+}
+
+struct Checker<'a> {
+    generators: &'a GeneratorTable,
+    edges: HashMap<*const LinkedSection, Rc<Edges>>,
+    has_choice: HashMap<*const LinkedSection, bool>,
+    errors: Diagnostics,
+}
+
+/// Prove that every text written with the library decodes to the data it was written from.
+pub fn check(entry: &Rc<LinkedSection>, generators: &GeneratorTable, budget: Budget) -> Result<()> {
+    let mut checker = Checker {
+        generators,
+        edges: HashMap::new(),
+        has_choice: HashMap::new(),
+        errors: Diagnostics::default(),
+    };
+
+    // 没有任何选择的话编码器一个比特也读不了，会一直写下去
+    if !checker.has_choice(entry) {
+        return Err(error_at(
+            entry.info.span.as_ref(),
+            anyhow!(
+                "section [{}] always writes the same text, so it cannot carry any data",
+                entry.info
+            ),
+        ));
+    }
+
+    if let Err(err) = ambiguity::check(entry, generators, budget) {
+        checker.errors.push(err);
+    }
+
+    let edges = checker.section_edges(entry);
+    // 上一段entry的结尾接着下一段的开头
+    if let Some(err) = checker.check_boundary(&edges, &edges) {
+        checker.errors.push(error_at(entry.info.span.as_ref(), err));
+    }
+    checker.errors.into_result()
+}
+
+impl Checker<'_> {
+    fn has_choice(&mut self, sec: &Rc<LinkedSection>) -> bool {
+        if let Some(&known) = self.has_choice.get(&Rc::as_ptr(sec)) {
+            return known;
+        }
+
+        let mut found = sec.rules.len() > 1;
+        for seg in sec.rules.iter().flat_map(|rule| &rule.segs) {
+            if found {
+                break;
+            }
+            found = match seg {
+                LinkedSeg::Use(inner) => self.has_choice(inner),
+                LinkedSeg::Generate(name) => self
+                    .generators
+                    .get(name)
+                    .is_some_and(|generator| generator.count() > 1),
+                _ => false,
+            };
+        }
+
+        self.has_choice.insert(Rc::as_ptr(sec), found);
+        found
+    }
+
+    fn section_edges(&mut self, sec: &Rc<LinkedSection>) -> Rc<Edges> {
+        if let Some(edges) = self.edges.get(&Rc::as_ptr(sec)) {
+            return edges.clone();
+        }
+
+        let origin = format!("section `{}`", sec.info.qualified_name());
+        let mut edges = Edges::default();
+        for rule in &sec.rules {
+            edges.union(&self.rule_edges(&rule.segs, &origin, rule.span.as_ref()));
+        }
+
+        let edges = Rc::new(edges);
+        self.edges.insert(Rc::as_ptr(sec), edges.clone());
+        edges
+    }
+
+    /// Edges of a rule, checking where its segments meet on the way.
+    fn rule_edges(&mut self, segs: &[LinkedSeg], origin: &str, span: Option<&Span>) -> Edges {
+        let mut seg_edges: Vec<Rc<Edges>> = Vec::with_capacity(segs.len());
+        let mut rule = Edges {
+            nullable: true,
+            ..Edges::default()
+        };
+        // 到目前为止可能出现在结尾的部分，中间的空片段不算分隔
+        let mut left: Option<Edges> = None;
+
+        for seg in segs {
+            let edges = match seg {
+                LinkedSeg::Text(t) => Rc::new(Edges::word(t, origin)),
+                LinkedSeg::Use(inner) => self.section_edges(inner),
+                LinkedSeg::Generate(name) => Rc::new(self.generator_edges(name)),
+                &LinkedSeg::Recall { seg, .. } => seg_edges[seg as usize].clone(),
+                LinkedSeg::Attr { values, .. } => {
+                    let mut edges = Edges::default();
+                    for value in values {
+                        edges.add_word(value, origin);
+                    }
+                    Rc::new(edges)
+                }
+            };
+
+            if let Some(left) = &left {
+                if let Some(err) = self.check_boundary(left, &edges) {
+                    self.errors.push(error_at(span, err));
+                }
+            }
+
+            if rule.nullable {
+                for (&ch, example) in &edges.first {
+                    rule.first.entry(ch).or_insert_with(|| example.clone());
+                }
+            }
+            rule.nullable &= edges.nullable;
+
+            let mut new_left = Edges::default();
+            new_left.union(&edges);
+            if edges.nullable {
+                if let Some(left) = &left {
+                    new_left.union(left);
+                }
+            }
+            left = Some(new_left);
+            seg_edges.push(edges);
+        }
+
+        if let Some(left) = left {
+            rule.last = left.last;
+            rule.last_generators = left.last_generators;
+        }
+        rule
+    }
+
+    fn generator_edges(&self, name: &str) -> Edges {
+        let mut edges = Edges::default();
+        if let Some(generator) = self.generators.get(name) {
+            let origin = format!("generator `{name}`");
+            for value in 0..generator.count().min(GENERATOR_SAMPLES) {
+                edges.add_word(&generator.encode(value), &origin);
+            }
+        }
+        edges.last_generators.insert(name.to_owned());
+        edges
+    }
+
+    /// Find a text where `left` is followed by `right` that does not read back
+    /// as it was written.
+    fn check_boundary(&self, left: &Edges, right: &Edges) -> Option<Error> {
+        // 只有组合字符之类的才会和前一个字符一起被规范化
+        let may_merge = |ch: char| {
+            canonical_combining_class(ch) != 0
+                || is_nfc_quick(std::iter::once(ch)) != IsNormalized::Yes
+        };
+        for (_, (right_word, right_origin)) in right.first.iter().filter(|(&ch, _)| may_merge(ch)) {
+            for (left_word, left_origin) in left.last.values() {
+                let text = format!("{left_word}{right_word}");
+                let normalized: String = text.nfc().collect();
+                if normalized != text {
+                    return Some(anyhow!(
+                        "`{left_word}` from {left_origin} followed by `{right_word}` \
+                        from {right_origin} becomes `{normalized}` after Unicode \
+                        normalization, the decoder cannot split it back"
+                    ));
+                }
+            }
+        }
+
+        for name in &left.last_generators {
+            let Some(generator) = self.generators.get(name) else {
+                continue;
+            };
+            for value in 0..generator.count().min(GENERATOR_SAMPLES) {
+                let written = generator.encode(value);
+                for (right_word, right_origin) in right.first.values() {
+                    let text = format!("{written}{right_word}");
+                    match generator.decode(&text) {
+                        Some((read, len)) if read == value && len == written.len() => {}
+                        Some((_, len)) if text.is_char_boundary(len) => {
+                            return Some(anyhow!(
+                                "`{written}` from generator `{name}` followed by `{right_word}` \
+                                from {right_origin} is read back as `{}` from generator \
+                                `{name}`, so `{text}` has two meanings",
+                                &text[..len]
+                            ))
+                        }
+                        _ => {
+                            return Some(anyhow!(
+                                "`{written}` from generator `{name}` followed by `{right_word}` \
+                                from {right_origin} cannot be read back by the generator"
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[test]
+fn test_decodability() {
+    use super::{builder::RulePart, compile_from, CompileOptions, LibraryBuilder, MemorySources};
+    use crate::generator::Generator;
+
+    // 组合用的尖音符会和前一个词的结尾合成一个字
+    let mut builder = LibraryBuilder::new();
+    builder
+        .add_rule(
+            "entry",
+            [RulePart::Section("a".into()), RulePart::Section("b".into())],
+        )
+        .add_word("a", "e")
+        .add_word("a", "o")
+        .add_word("b", "\u{301}x")
+        .add_word("b", "y");
+    let err = builder.build().unwrap_err().to_string();
+    assert!(err.contains("Unicode normalization"), "{err}");
+
+    let mut builder = LibraryBuilder::new();
+    builder.add_word("entry", "你好");
+    assert!(builder.build().is_err());
+
+    struct Number;
+
+    impl Generator for Number {
+        fn count(&self) -> u32 {
+            100
+        }
+
+        fn encode(&self, value: u32) -> String {
+            value.to_string()
+        }
+
+        fn decode(&self, input: &str) -> Option<(u32, usize)> {
+            let len = input.bytes().take_while(u8::is_ascii_digit).count().min(2);
+            Some((input.get(..len)?.parse().ok()?, len))
+        }
+    }
+
+    let mut options = CompileOptions::default();
+    options.generators.register("数", Number);
+    let compile = |entry: &str| {
+        let sources = MemorySources::from_iter([("lib/entry.txt", format!("[entry]\n{entry}\n"))]);
+        compile_from(&sources, "lib", &options)
+    };
+
+    // 一个数字接着下一个数字会被读成一个两位数
+    let err = compile("{数}").err().unwrap().to_string();
+    assert!(err.contains("has two meanings"), "{err}");
+    assert!(compile("{数}个").is_ok());
+}
@@ -0,0 +1,202 @@
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Error;
+
+use crate::share_str::ShareStr;
+
+/// A place in a library file, kept as the text found there.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub file: Rc<Path>,
+    text: ShareStr,
+}
+
+impl Span {
+    /// `text` must be shared from the whole content of `file`.
+    pub fn new(file: Rc<Path>, text: ShareStr) -> Self {
+        Span { file, text }
+    }
+
+    /// Another place in the same file.
+    pub fn at(&self, text: &ShareStr) -> Self {
+        Span {
+            file: self.file.clone(),
+            text: text.clone(),
+        }
+    }
+
+    pub fn locate(&self) -> Location {
+        let source = self.text.source();
+        let offset = self.text.offset();
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .find('\n')
+            .map_or(source.len(), |i| offset + i);
+        let highlight_end = (offset + self.text.len()).min(line_end);
+
+        Location {
+            file: self.file.to_path_buf(),
+            line: source[..offset].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            source_line: source[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_owned(),
+            indent: width(&source[line_start..offset]),
+            highlight: width(&source[offset..highlight_end]).max(1),
+        }
+    }
+}
+
+/// Where an error is, resolved so that it can outlive the compiler.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub file: PathBuf,
+    /// Starts from 1.
+    pub line: usize,
+    /// Starts from 1, counted in characters.
+    pub column: usize,
+    pub source_line: String,
+    // 在终端里显示的宽度，用来对齐脱字符
+    indent: usize,
+    highlight: usize,
+}
+
+/// An error found in the library source.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        let Some(loc) = &self.location else {
+            return Ok(());
+        };
+
+        let line = loc.line.to_string();
+        let pad = " ".repeat(line.len());
+        write!(
+            f,
+            "\n{pad}--> {}:{}:{}\n{pad} |\n{line} | {}\n{pad} | {}{}",
+            loc.file.display(),
+            loc.line,
+            loc.column,
+            loc.source_line,
+            " ".repeat(loc.indent),
+            "^".repeat(loc.highlight)
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// Every error found while compiling a library.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub(super) fn push(&mut self, err: Error) {
+        if err.is::<Reported>() {
+            return;
+        }
+        match err.downcast::<Diagnostics>() {
+            Ok(all) => self.0.extend(all.0),
+            Err(err) => match err.downcast::<Diagnostic>() {
+                Ok(diag) => self.0.push(diag),
+                Err(err) => self.0.push(Diagnostic {
+                    message: format!("{err:#}"),
+                    location: None,
+                }),
+            },
+        }
+    }
+
+    pub(super) fn into_result(self) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self.into())
+        }
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diag) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+            write!(f, "{diag}")?;
+        }
+        if self.0.len() > 1 {
+            write!(f, "\n\n{} errors found", self.0.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Failure of a section whose error has been collected already,
+/// so that sections referencing it do not report it again.
+#[derive(Debug)]
+pub(super) struct Reported;
+
+impl Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error already reported")
+    }
+}
+
+impl std::error::Error for Reported {}
+
+/// Give `err` a location, unless it knows better where it is.
+pub(super) fn error_at(span: Option<&Span>, err: Error) -> Error {
+    if err.is::<Diagnostic>() || err.is::<Diagnostics>() || err.is::<Reported>() {
+        return err;
+    }
+    Diagnostic {
+        message: format!("{err:#}"),
+        location: span.map(Span::locate),
+    }
+    .into()
+}
+
+fn width(s: &str) -> usize {
+    s.chars()
+        .map(|ch| match ch {
+            '\u{1100}'..='\u{115f}'
+            | '\u{2e80}'..='\u{a4cf}'
+            | '\u{ac00}'..='\u{d7a3}'
+            | '\u{f900}'..='\u{faff}'
+            | '\u{fe30}'..='\u{fe4f}'
+            | '\u{ff00}'..='\u{ff60}'
+            | '\u{ffe0}'..='\u{ffe6}'
+            | '\u{20000}'..='\u{3fffd}' => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+#[test]
+fn test_locate() {
+    let source = ShareStr::new("[entry]\n我的{名词}\r\n");
+    let span = Span::new(
+        Path::new("entry.txt").into(),
+        source.recognize(&source[15..21]).unwrap(),
+    );
+    let diag = Diagnostic {
+        message: "bad".into(),
+        location: Some(span.locate()),
+    };
+    assert_eq!(
+        diag.to_string(),
+        "bad\n --> entry.txt:2:4\n  |\n2 | 我的{名词}\n  |      ^^^^"
+    );
+}
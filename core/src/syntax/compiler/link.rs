@@ -1,106 +1,1084 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     rc::Rc,
 };
 
 use super::{
-    parse_tokens::{ExprSection, ExprSectionBody, ExprSeg, SecInfo},
+    decodability,
+    diagnostic::{error_at, Diagnostics, Reported, Span},
+    parse_tokens::{
+        join_scope, ExprRef, ExprRule, ExprSection, ExprSectionBody, ExprSeg, SecInfo, SectionKind,
+    },
     searcher::{self, Trie},
+    Budget, CompileOptions,
 };
 use anyhow::{anyhow, Result};
 
-use crate::share_str::ShareStr;
+use crate::{generator::GeneratorTable, share_str::ShareStr};
 
-pub type LinkedSectionBody = Vec<Vec<LinkedSeg>>;
+pub type LinkedSectionBody = Vec<LinkedRule>;
 
-pub fn link_secs(sections: Vec<ExprSection>) -> Result<Rc<LinkedSection>> {
-    SectionTable::parse(sections)?
+pub fn link_secs(
+    sections: Vec<ExprSection>,
+    options: &CompileOptions,
+) -> Result<Rc<LinkedSection>> {
+    let budget = Budget::new(&options.limits);
+    let entry = SectionTable::parse(sections, &options.generators, budget)?
         .table
         .remove("entry")
-        .ok_or_else(|| anyhow!("a section named `entry` must be defined"))
+        .ok_or_else(|| anyhow!("a section named `entry` must be defined"))?;
+    decodability::check(&entry, &options.generators, budget)?;
+    Ok(entry)
 }
 
+#[derive(Clone)]
 pub enum LinkedSeg {
     Text(ShareStr),
     Use(Rc<LinkedSection>),
+    Generate(ShareStr),
+    /// Repeat the text produced by the `seg`th segment of the same rule.
+    Recall {
+        seg: u32,
+        name: ShareStr,
+    },
+    /// Attribute of the word chosen by the `seg`th segment, one value per rule of its section.
+    Attr {
+        seg: u32,
+        values: Vec<ShareStr>,
+        name: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct LinkedRule {
+    pub segs: Vec<LinkedSeg>,
+    pub tags: Vec<ShareStr>,
+    pub attrs: HashMap<ShareStr, ShareStr>,
+    pub weight: u32,
+    pub span: Option<Span>,
 }
 
 pub struct LinkedSection {
-    pub rules: Vec<Vec<LinkedSeg>>,
+    pub rules: LinkedSectionBody,
     pub info: SecInfo,
     pub search: Rc<Trie>,
+    /// Neither this section nor any section below it is decoded by searching.
+    pub deterministic: bool,
 }
 
-struct SectionTable {
-    table: HashMap<ShareStr, Rc<LinkedSection>>,
+impl LinkedSection {
+    fn new(rules: LinkedSectionBody, info: SecInfo, budget: Budget) -> Result<Self> {
+        let search: Rc<Trie> = searcher::compile(&rules, &info, budget)?.into();
+        let deterministic = !matches!(*search, Trie::Search)
+            && rules
+                .iter()
+                .flat_map(|rule| &rule.segs)
+                .all(|seg| !matches!(seg, LinkedSeg::Use(u) if !u.deterministic));
+        Ok(LinkedSection {
+            rules,
+            info,
+            search,
+            deterministic,
+        })
+    }
 }
 
-impl SectionTable {
-    pub fn parse(raw_sections: Vec<ExprSection>) -> Result<Self> {
+/// Sections and macros keyed by their qualified names, like `adj::普通形容词`.
+struct SectionTable<'a> {
+    table: HashMap<String, Rc<LinkedSection>>,
+    macros: HashMap<String, Rc<ExprSection>>,
+    /// Sections linked when first referenced, so a section may be used before its definition.
+    pending: HashMap<String, ExprSection>,
+    /// `[+name]` sections, keyed by the qualified name of the section they extend.
+    extensions: HashMap<String, Vec<ExprSection>>,
+    /// Sections being linked, innermost last.
+    linking: Vec<String>,
+    /// Sections that cannot be linked, their errors have been reported.
+    failed: HashSet<String>,
+    errors: Diagnostics,
+    generators: &'a GeneratorTable,
+    budget: Budget<'a>,
+    expand_depth: usize,
+}
+
+/// What a name in a rule stands for after linking.
+#[derive(Clone)]
+enum Target {
+    Section(Rc<LinkedSection>),
+    Generate(ShareStr),
+}
+
+/// Arguments bound to the parameters of the macro being expanded.
+type Env = HashMap<ShareStr, Target>;
+
+const MAX_EXPAND_DEPTH: usize = 32;
+
+impl<'a> SectionTable<'a> {
+    pub fn parse(
+        raw_sections: Vec<ExprSection>,
+        generators: &'a GeneratorTable,
+        budget: Budget<'a>,
+    ) -> Result<Self> {
         let mut this = SectionTable {
             table: HashMap::new(),
+            macros: HashMap::new(),
+            pending: HashMap::new(),
+            extensions: HashMap::new(),
+            linking: Vec::new(),
+            failed: HashSet::new(),
+            errors: Diagnostics::default(),
+            generators,
+            budget,
+            expand_depth: 0,
         };
 
-        for ExprSection {
-            rules,
-            info: this_sec_info,
-        } in raw_sections
-        {
-            if rules.is_empty() {
+        let (sections, extensions) = collect_extensions(raw_sections, &mut this.errors);
+        this.extensions = extensions;
+
+        let mut order = Vec::new();
+        // 每个名字第一次定义的地方，出错没能链接的段落也算在内
+        let mut defined: HashMap<String, SecInfo> = HashMap::new();
+        for sec in sections {
+            let this_sec_info = &sec.info;
+            let span = this_sec_info.span.as_ref();
+            match defined.entry(this_sec_info.qualified_name()) {
+                Entry::Occupied(old) => {
+                    let old_sec = old.get();
+                    let err = if this_sec_info.file == old_sec.file {
+                        anyhow!(
+                            "section [{this_sec_info}] is defined more than once in the same file"
+                        )
+                    } else {
+                        anyhow!(
+                            "cannot re-define section [{this_sec_info}], \
+                            because a namesake [{old_sec}] has been defined"
+                        )
+                    };
+                    this.errors.push(error_at(span, err));
+                    continue;
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(this_sec_info.clone());
+                }
+            }
+
+            if sec.rules.is_empty()
+                && matches!(sec.kind, SectionKind::Rules)
+                && !this
+                    .extensions
+                    .contains_key(&this_sec_info.qualified_name())
+            {
+                this.errors.push(error_at(
+                    span,
+                    anyhow!("section [{this_sec_info}] is empty, it must contains at least 1 rule"),
+                ));
+                this.failed.insert(this_sec_info.qualified_name());
+                continue;
+            }
+            if this.generators.contains(&this_sec_info.name) {
+                this.errors.push(error_at(
+                    span,
+                    anyhow!(
+                        "cannot define section [{this_sec_info}], \
+                        because a namesake generator has been registered"
+                    ),
+                ));
+                continue;
+            }
+
+            let qualified_name = this_sec_info.qualified_name();
+
+            if !sec.params.is_empty() {
+                // 宏在被引用时才展开
+                this.macros.insert(qualified_name, Rc::new(sec));
+                continue;
+            }
+
+            order.push(qualified_name.clone());
+            this.pending.insert(qualified_name, sec);
+        }
+
+        for name in order {
+            let Some(sec) = this.pending.get(&name) else {
+                // 已经作为别的段落的依赖处理过了
+                continue;
+            };
+            let span = sec.info.span.clone();
+            if let Err(err) = this.link_pending(&name) {
+                this.errors.push(error_at(span.as_ref(), err));
+            }
+        }
+
+        std::mem::take(&mut this.errors).into_result()?;
+        Ok(this)
+    }
+
+    fn link_pending(&mut self, qualified_name: &str) -> Result<Rc<LinkedSection>> {
+        if let Some(rc) = self.table.get(qualified_name) {
+            return Ok(rc.clone());
+        }
+
+        let ExprSection {
+            rules, info, kind, ..
+        } = self.pending.remove(qualified_name).unwrap();
+
+        self.linking.push(qualified_name.to_owned());
+        let linked = match kind {
+            SectionKind::Union(parts) => self.link_union(info, &parts),
+            _ => self.link_section(info, rules, qualified_name, &Env::new()),
+        };
+        self.linking.pop();
+
+        let linked = linked.inspect_err(|_| {
+            self.failed.insert(qualified_name.to_owned());
+        })?;
+        self.table.insert(qualified_name.to_owned(), linked.clone());
+        Ok(linked)
+    }
+
+    /// Link the rules of a section followed by those of the sections extending
+    /// `extended`, the name the section is defined under.
+    fn link_section(
+        &mut self,
+        info: SecInfo,
+        rules: ExprSectionBody,
+        extended: &str,
+        env: &Env,
+    ) -> Result<Rc<LinkedSection>> {
+        let extensions: Vec<(SecInfo, ExprSectionBody)> = self
+            .extensions
+            .get(extended)
+            .into_iter()
+            .flatten()
+            .map(|ext| (ext.info.clone(), ext.rules.clone()))
+            .collect();
+
+        let normalized: Vec<bool> = (rules.iter())
+            .chain(extensions.iter().flat_map(|(_, rules)| rules))
+            .map(|rule| rule.normalized)
+            .collect();
+
+        // 扩充的规则在写下它们的文件和命名空间里查找引用的段落
+        let mut new_rules = Vec::new();
+        let mut failed = false;
+        for (rules_info, rules) in [(info.clone(), rules)].into_iter().chain(extensions) {
+            match self.link_segs(&rules_info, rules, env) {
+                Ok(rules) => new_rules.extend(rules),
+                Err(_) => failed = true,
+            }
+        }
+        if failed {
+            return Err(Reported.into());
+        }
+
+        let mut words = HashMap::new();
+        for (i, rule) in new_rules.iter().enumerate() {
+            let word = searcher::display_rule(&rule.segs);
+            if let Some(first) = words.insert(word.clone(), i) {
+                // 写法一样的重复就不必提规范化了
+                let how = if normalized[first] || normalized[i] {
+                    " after Unicode normalization"
+                } else {
+                    ""
+                };
+                return Err(error_at(
+                    rule.span.as_ref(),
+                    anyhow!("word `{word}` appears more than once in section [{info}]{how}"),
+                ));
+            }
+        }
+
+        Ok(Rc::new(LinkedSection::new(new_rules, info, self.budget)?))
+    }
+
+    fn link_union(&mut self, info: SecInfo, parts: &[ExprRef]) -> Result<Rc<LinkedSection>> {
+        // 直接摊平所有部分的规则，免得在嵌套的二分上浪费比特
+        let mut rules = Vec::new();
+        let mut origins: HashMap<String, Rc<LinkedSection>> = HashMap::new();
+        for part in parts {
+            let Target::Section(sec) = self.resolve(part, &Env::new(), &info)? else {
                 return Err(anyhow!(
-                    "section [{this_sec_info}] is empty, it must contains at least 1 rule"
+                    "union [{info}] can only merge sections, but `{part}` is a generator"
                 ));
+            };
+
+            for rule in &sec.rules {
+                let word = searcher::display_rule(&rule.segs);
+                if let Some(other) = origins.insert(word.clone(), sec.clone()) {
+                    return Err(anyhow!(
+                        "word `{word}` is in both [{}] and [{}], \
+                        which are merged into union [{info}]",
+                        other.info,
+                        sec.info
+                    ));
+                }
+                rules.push(rule.clone());
             }
-            let new_rules = this.link_segs(this_sec_info.clone(), rules)?;
+        }
 
-            match this.table.entry(this_sec_info.name.clone()) {
-                Entry::Occupied(occ) => {
-                    let old_sec = &occ.get().info;
-                    if this_sec_info.file != old_sec.file {
-                        return Err(anyhow!(
-                            "cannot re-define section [{this_sec_info}], \
-                            because a namesake [{old_sec}] has been defined"
-                        ));
-                    }
+        Ok(Rc::new(LinkedSection::new(rules, info, self.budget)?))
+    }
+
+    fn resolve(&mut self, target: &ExprRef, env: &Env, sec_info: &SecInfo) -> Result<Target> {
+        let resolved = self.resolve_name(target, env, sec_info)?;
+        if target.filter.is_empty() {
+            return Ok(resolved);
+        }
+
+        let Target::Section(sec) = resolved else {
+            return Err(anyhow!(
+                "generator `{}` has no tags, but section [{sec_info}] filters it by tags",
+                target.name
+            ));
+        };
+
+        let mut filter: Vec<&str> = target.filter.iter().map(ShareStr::as_str).collect();
+        filter.sort_unstable();
+        filter.dedup();
+        match self.filter_section(&sec, &filter)? {
+            Some(filtered) => Ok(Target::Section(filtered)),
+            None => Err(anyhow!(
+                "no word of section [{}] is tagged `#{}`, but section [{sec_info}] asks for one",
+                sec.info,
+                filter.join(" #")
+            )),
+        }
+    }
+
+    /// Derive a section of the words that have all tags in `filter`. A rule that only
+    /// references another section is narrowed to the words of that section with the tags.
+    fn filter_section(
+        &mut self,
+        sec: &Rc<LinkedSection>,
+        filter: &[&str],
+    ) -> Result<Option<Rc<LinkedSection>>> {
+        let name = format!("{}#{}", sec.info.name, filter.join("#"));
+        let qualified_name = join_scope(&sec.info.scope, &name);
+        if let Some(rc) = self.table.get(&qualified_name) {
+            return Ok(Some(rc.clone()));
+        }
+
+        let mut rules = Vec::new();
+        for rule in &sec.rules {
+            let missing: Vec<&str> = filter
+                .iter()
+                .copied()
+                .filter(|t| !rule.tags.iter().any(|tag| tag.as_str() == *t))
+                .collect();
+            if missing.is_empty() {
+                rules.push(rule.clone());
+            } else if let [LinkedSeg::Use(inner)] = &rule.segs[..] {
+                if let Some(filtered) = self.filter_section(inner, &missing)? {
+                    rules.push(LinkedRule {
+                        segs: vec![LinkedSeg::Use(filtered)],
+                        ..rule.clone()
+                    });
                 }
+            }
+        }
+        if rules.is_empty() {
+            return Ok(None);
+        }
 
-                Entry::Vacant(vac) => {
-                    vac.insert(Rc::new(LinkedSection {
-                        search: searcher::compile(&new_rules, &this_sec_info)?.into(),
-                        rules: new_rules,
-                        info: this_sec_info,
-                    }));
+        let info = SecInfo {
+            name: ShareStr::new(&name),
+            ..sec.info.clone()
+        };
+        let linked = Rc::new(LinkedSection::new(rules, info, self.budget)?);
+        self.table.insert(qualified_name, linked.clone());
+        Ok(Some(linked))
+    }
+
+    fn resolve_name(&mut self, target: &ExprRef, env: &Env, sec_info: &SecInfo) -> Result<Target> {
+        if let Some(bound) = env.get(&target.name) {
+            if !target.args.is_empty() {
+                return Err(anyhow!(
+                    "parameter `{}` cannot take arguments, but section [{sec_info}] passes some",
+                    target.name
+                ));
+            }
+            return Ok(bound.clone());
+        }
+
+        let Some(qualified_name) = self.lookup(&target.name, sec_info) else {
+            if let Some(generator) = self.generators.get(&target.name) {
+                if !target.args.is_empty() {
+                    return Err(anyhow!(
+                        "generator `{}` cannot take arguments, but section [{sec_info}] passes some",
+                        target.name
+                    ));
+                }
+                if generator.count() == 0 {
+                    return Err(anyhow!(
+                        "generator `{}` has no values, but section [{sec_info}] uses it",
+                        target.name
+                    ));
                 }
+                return Ok(Target::Generate(target.name.clone()));
             }
+            return Err(anyhow!(
+                "section `{}` is not defined, but referenced by section [{sec_info}]",
+                target.name
+            ));
+        };
+
+        if self.failed.contains(&qualified_name) {
+            return Err(Reported.into());
         }
 
-        Ok(this)
+        let Some(mac) = self.macros.get(&qualified_name).cloned() else {
+            if !target.args.is_empty() {
+                return Err(anyhow!(
+                    "section `{}` is not a macro, but section [{sec_info}] passes arguments to it",
+                    target.name
+                ));
+            }
+
+            if let Some(pos) = self.linking.iter().position(|n| *n == qualified_name) {
+                let mut cycle = self.linking[pos..].to_vec();
+                cycle.push(qualified_name);
+                return Err(anyhow!(
+                    "sections reference each other in a cycle: {}, \
+                    but a section cannot contain itself",
+                    cycle.join(" -> ")
+                ));
+            }
+
+            let rc = self.link_pending(&qualified_name)?;
+            check_visible(&rc.info, sec_info)?;
+            return Ok(Target::Section(rc));
+        };
+        check_visible(&mac.info, sec_info)?;
+
+        if target.args.len() != mac.params.len() {
+            return Err(anyhow!(
+                "macro [{}] takes {} argument(s), but section [{sec_info}] passes {}",
+                mac.info,
+                mac.params.len(),
+                target.args.len()
+            ));
+        }
+
+        let mut args = Vec::with_capacity(target.args.len());
+        for arg in &target.args {
+            args.push(self.resolve(arg, env, sec_info)?);
+        }
+
+        // 展开后的名字只由宏名和实参决定，保证每次编译结果一致
+        let arg_names: Vec<String> = args.iter().map(Target::name).collect();
+        let name = format!("{}({})", mac.info.name, arg_names.join(","));
+        if let Some(rc) = self.table.get(&join_scope(&mac.info.scope, &name)) {
+            return Ok(Target::Section(rc.clone()));
+        }
+
+        if self.expand_depth >= MAX_EXPAND_DEPTH {
+            return Err(anyhow!(
+                "macro [{}] is expanded too deeply when expanding `{name}`, \
+                is it referencing itself?",
+                mac.info
+            ));
+        }
+
+        let info = SecInfo {
+            name: ShareStr::new(&name),
+            ..mac.info.clone()
+        };
+        let env = mac.params.iter().cloned().zip(args).collect();
+
+        self.expand_depth += 1;
+        let linked = self.link_section(info, mac.rules.clone(), &mac.info.qualified_name(), &env);
+        self.expand_depth -= 1;
+
+        let linked = linked?;
+        self.table
+            .insert(linked.info.qualified_name(), linked.clone());
+        Ok(Target::Section(linked))
     }
 
-    fn link_segs(&self, sec_info: SecInfo, rules: ExprSectionBody) -> Result<LinkedSectionBody> {
+    /// Find the qualified name of a section or macro seen from `sec_info`,
+    /// searching its own namespace first and then each enclosing one.
+    fn lookup(&self, name: &str, sec_info: &SecInfo) -> Option<String> {
+        scope_candidates(&sec_info.scope, name).find(|qn| {
+            self.table.contains_key(qn)
+                || self.macros.contains_key(qn)
+                || self.pending.contains_key(qn)
+                || self.linking.contains(qn)
+                || self.failed.contains(qn)
+        })
+    }
+
+    fn link_segs(
+        &mut self,
+        sec_info: &SecInfo,
+        rules: ExprSectionBody,
+        env: &Env,
+    ) -> Result<LinkedSectionBody> {
         let mut new_rules = Vec::new();
+        let mut failed = false;
 
+        // 一条规则出错后接着检查其余的规则，好一次报告所有错误
         for rule in rules {
-            let mut new_rule = Vec::new();
-
-            for seg in rule {
-                let new_seg = match seg {
-                    ExprSeg::Text(txt) => LinkedSeg::Text(txt),
-                    ExprSeg::Use(r) => match self.table.get(&r) {
-                        Some(rc) => LinkedSeg::Use(rc.clone()),
-                        None => {
-                            return Err(anyhow!(
-                            "section `{r}` is not defined, but referenced by section [{sec_info}]"
-                        ))
+            match self.link_rule(sec_info, rule, env) {
+                Ok(rule) => new_rules.push(rule),
+                Err(err) => {
+                    self.errors.push(err);
+                    failed = true;
+                }
+            }
+        }
+
+        if failed {
+            return Err(Reported.into());
+        }
+        Ok(new_rules)
+    }
+
+    fn link_rule(&mut self, sec_info: &SecInfo, rule: ExprRule, env: &Env) -> Result<LinkedRule> {
+        let span = rule.span;
+        let mut new_rule = Vec::new();
+        let mut captures = HashMap::new();
+        // 每个片段在规则里写下的名字，用于查找属性
+        let mut written_names = Vec::new();
+
+        for seg in rule.segs {
+            let mut written_name = None;
+            let seg_span = span.as_ref().map(|s| s.at(seg.source_text()));
+            let at = |err| error_at(seg_span.as_ref(), err);
+            let new_seg = match seg {
+                ExprSeg::Text(txt) | ExprSeg::Quoted(txt) => LinkedSeg::Text(txt),
+                ExprSeg::Use { target, capture } => {
+                    if let Some(cap) = capture {
+                        if captures
+                            .insert(cap.clone(), new_rule.len() as u32)
+                            .is_some()
+                        {
+                            return Err(at(anyhow!(
+                                "capture `{cap}` is defined more than once \
+                                    in a rule of section [{sec_info}]"
+                            )));
                         }
-                    },
-                };
-                new_rule.push(new_seg);
+                    }
+
+                    written_name = Some(target.name.clone());
+                    match self.resolve(&target, env, sec_info).map_err(at)? {
+                        Target::Section(rc) => LinkedSeg::Use(rc),
+                        Target::Generate(name) => LinkedSeg::Generate(name),
+                    }
+                }
+                ExprSeg::Recall { capture, attr } => {
+                    let Some(&seg) = captures.get(&capture) else {
+                        return Err(at(anyhow!(
+                            "capture `{capture}` is used by section [{sec_info}] \
+                                before being defined in the same rule"
+                        )));
+                    };
+
+                    match attr {
+                        None => LinkedSeg::Recall { seg, name: capture },
+                        Some(attr) => link_attr(
+                            &new_rule,
+                            seg,
+                            &attr,
+                            format!("${capture}.{attr}"),
+                            sec_info,
+                        )
+                        .map_err(at)?,
+                    }
+                }
+                ExprSeg::Attr { section, attr } => {
+                    let found = written_names
+                        .iter()
+                        .rposition(|name: &Option<ShareStr>| name.as_ref() == Some(&section));
+                    let Some(seg) = found else {
+                        return Err(at(anyhow!(
+                            "attribute `{section}.{attr}` is used by section [{sec_info}], \
+                                but `{section}` is not referenced before it in the same rule"
+                        )));
+                    };
+
+                    link_attr(
+                        &new_rule,
+                        seg as u32,
+                        &attr,
+                        format!("{section}.{attr}"),
+                        sec_info,
+                    )
+                    .map_err(at)?
+                }
+            };
+            new_rule.push(new_seg);
+            written_names.push(written_name);
+        }
+
+        let mut attrs = HashMap::new();
+        for (key, value) in rule.attrs {
+            if attrs.insert(key.clone(), value).is_some() {
+                return Err(error_at(
+                    span.as_ref(),
+                    anyhow!(
+                        "attribute `{key}` is given more than once \
+                            in a rule of section [{sec_info}]"
+                    ),
+                ));
             }
+        }
 
-            new_rules.push(new_rule);
+        Ok(LinkedRule {
+            segs: new_rule,
+            tags: rule.tags,
+            attrs,
+            weight: rule.weight,
+            span,
+        })
+    }
+}
+
+impl Target {
+    fn name(&self) -> String {
+        match self {
+            Target::Section(sec) => sec.info.qualified_name(),
+            Target::Generate(name) => name.to_string(),
         }
+    }
+}
 
-        Ok(new_rules)
+/// Qualified names `name` may stand for in `scope`, innermost namespace first.
+fn scope_candidates<'s>(scope: &'s str, name: &'s str) -> impl Iterator<Item = String> + 's {
+    let mut scope = Some(scope);
+    std::iter::from_fn(move || {
+        let current = scope?;
+        scope = (!current.is_empty())
+            .then(|| current.rsplit_once("::").map_or("", |(parent, _)| parent));
+        Some(join_scope(current, name))
+    })
+}
+
+/// Drop sections of an inherited library that are redefined by a library extending it,
+/// returning a note for each of them.
+pub fn apply_overrides(raw_sections: Vec<ExprSection>) -> (Vec<ExprSection>, Vec<String>) {
+    // 每个名字只保留继承层数最少的定义
+    let mut winners: HashMap<String, &ExprSection> = HashMap::new();
+    for sec in &raw_sections {
+        if matches!(sec.kind, SectionKind::Extend) {
+            continue;
+        }
+        let winner = winners.entry(sec.info.qualified_name()).or_insert(sec);
+        if sec.inherit_depth < winner.inherit_depth {
+            *winner = sec;
+        }
+    }
+    let winners: HashMap<String, (u32, SecInfo)> = winners
+        .into_iter()
+        .map(|(name, sec)| (name, (sec.inherit_depth, sec.info.clone())))
+        .collect();
+
+    let mut kept = Vec::new();
+    let mut notes = Vec::new();
+    for sec in raw_sections {
+        match winners.get(&sec.info.qualified_name()) {
+            // 被覆盖的段落在原库里的扩充也一起丢掉
+            Some((depth, winner)) if sec.inherit_depth > *depth => {
+                if !matches!(sec.kind, SectionKind::Extend) {
+                    notes.push(format!(
+                        "section [{}] is overridden by [{winner}]",
+                        sec.info
+                    ));
+                }
+            }
+            _ => kept.push(sec),
+        }
+    }
+
+    (kept, notes)
+}
+
+/// Separate the `[+name]` sections from the others, grouping them by the qualified
+/// name of the section they extend.
+fn collect_extensions(
+    raw_sections: Vec<ExprSection>,
+    errors: &mut Diagnostics,
+) -> (Vec<ExprSection>, HashMap<String, Vec<ExprSection>>) {
+    let (extensions, sections): (Vec<_>, Vec<_>) = raw_sections
+        .into_iter()
+        .partition(|sec| matches!(sec.kind, SectionKind::Extend));
+
+    // 同名的重复定义留给后面报错，这里只认第一个
+    let index: HashMap<String, &ExprSection> = sections
+        .iter()
+        .rev()
+        .map(|sec| (sec.info.qualified_name(), sec))
+        .collect();
+
+    let mut grouped: HashMap<String, Vec<ExprSection>> = HashMap::new();
+    for ext in extensions {
+        let found = scope_candidates(&ext.info.scope, &ext.info.name)
+            .find_map(|qn| index.get(&qn).copied());
+        let result = match found {
+            Some(base) => check_extension(base, &ext).map(|()| base.info.qualified_name()),
+            None => Err(anyhow!(
+                "section [{}] extends `{}`, which is not defined",
+                ext.info,
+                ext.info.name
+            )),
+        };
+        match result {
+            Ok(base_name) => grouped.entry(base_name).or_default().push(ext),
+            Err(err) => errors.push(error_at(ext.info.span.as_ref(), err)),
+        }
+    }
+
+    (sections, grouped)
+}
+
+fn check_extension(base: &ExprSection, ext: &ExprSection) -> Result<()> {
+    check_visible(&base.info, &ext.info)?;
+    if let SectionKind::Union(_) = base.kind {
+        return Err(anyhow!(
+            "section [{}] cannot extend union [{}], extend one of its parts instead",
+            ext.info,
+            base.info
+        ));
+    }
+    if ext.params != base.params {
+        return Err(anyhow!(
+            "section [{}] must declare the same parameters as [{}] it extends",
+            ext.info,
+            base.info
+        ));
+    }
+    Ok(())
+}
+
+fn check_visible(target: &SecInfo, sec_info: &SecInfo) -> Result<()> {
+    if target.private && target.file != sec_info.file {
+        return Err(anyhow!(
+            "section [{target}] is private to its file, but referenced by section [{sec_info}]"
+        ));
+    }
+    Ok(())
+}
+
+fn link_attr(
+    rule: &[LinkedSeg],
+    seg: u32,
+    attr: &str,
+    name: String,
+    sec_info: &SecInfo,
+) -> Result<LinkedSeg> {
+    let LinkedSeg::Use(sec) = &rule[seg as usize] else {
+        return Err(anyhow!(
+            "attribute `{name}` is used by section [{sec_info}], \
+            but only words chosen from a section have attributes"
+        ));
+    };
+
+    let values = sec
+        .rules
+        .iter()
+        .map(|r| {
+            r.attrs.get(attr).cloned().ok_or_else(|| {
+                anyhow!(
+                    "attribute `{name}` is used by section [{sec_info}], \
+                    but rule `{}` of section [{}] does not define `{attr}`",
+                    searcher::display_rule(&r.segs),
+                    sec.info
+                )
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(LinkedSeg::Attr { seg, values, name })
+}
+
+#[test]
+fn test_macro() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let compile = |entry: &str| {
+        let sources: MemorySources = [(
+            "lib/entry.txt",
+            format!("[形容词]\n好看\n难看\n[食物]\n饼\n面\n[家具]\n桌子\n椅子\n{entry}"),
+        )]
+        .into_iter()
+        .collect();
+        compile_from(&sources, "lib", &CompileOptions::default())
+    };
+
+    // 同样的实参只展开一次，每次编译段落的顺序都一样
+    let source =
+        "[修饰(名词)]\n{形容词}的{名词}\n[entry]\n{修饰(食物)}配{修饰(家具)}\n{修饰(家具)}了\n";
+    let compiled = compile(source).unwrap();
+    assert_eq!(
+        compiled.meta.section_names,
+        [
+            "entry",
+            "修饰(食物)",
+            "形容词",
+            "食物",
+            "修饰(家具)",
+            "家具"
+        ]
+    );
+    let map = compiled.map;
+    assert_eq!(
+        crate::file::save_lib(&map),
+        crate::file::save_lib(&compile(source).unwrap().map)
+    );
+    // 两条规则都要读过展开出来的`{形容词}的`才能分开
+    let text = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(crate::decode(&map, &text).unwrap(), b"fg2");
+
+    // 展开的段落由宏名和实参命名
+    let err = compile("[修饰(名词)]\n{没有}{名词}\n[entry]\n{修饰(食物)}\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.contains("section `没有` is not defined, but referenced by section [`修饰(食物)`"),
+        "{err}"
+    );
+
+    let err = compile("[修饰(名词)]\n{名词}\n[entry]\n{修饰(食物, 家具)}\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("takes 1 argument(s)"), "{err}");
+    let err = compile("[entry]\n{食物(家具)}\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("is not a macro"), "{err}");
+    let err = compile("[套(x)]\n{套(套(x))}\n[entry]\n{套(食物)}\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("expanded too deeply"), "{err}");
+}
+
+#[test]
+fn test_namespace() {
+    use super::{compile_from, CompileOptions, Compiled, MemorySources};
+
+    let compile = |entry: &str, words: &str| -> Result<Compiled> {
+        let sources: MemorySources = [("lib/entry.txt", entry), ("lib/词.txt", words)]
+            .into_iter()
+            .collect();
+        compile_from(&sources, "lib", &CompileOptions::default())
+    };
+    let words = "[食物]\n{物品}\n[物品]\n饼\n面\n[private 秘密]\n糖\n";
+
+    // 不带前缀的名字先在自己的文件里找，`词::`指名另一个文件的段落
+    let entry = "[include \"词.txt\" as 词]\n[entry]\n{词::食物}配{物品}\n[物品]\n桶\n锅\n";
+    let compiled = compile(entry, words).unwrap();
+    let mut names = compiled.meta.section_names.clone();
+    names.sort_unstable();
+    assert_eq!(names, ["entry", "物品", "词::物品", "词::食物"]);
+    assert!(crate::decode(&compiled.map, "饼配桶").is_ok());
+    assert!(crate::decode(&compiled.map, "桶配桶").is_err());
+
+    // 宏展开出来的段落用实参的全名命名
+    let entry = "[include \"词.txt\" as 词]\n[entry]\n{修饰(词::食物)}\n[修饰(x)]\n好{x}\n";
+    let compiled = compile(entry, words).unwrap();
+    assert!(compiled
+        .meta
+        .section_names
+        .iter()
+        .any(|name| name == "修饰(词::食物)"));
+
+    let entry = "[include \"词.txt\" as 词]\n[entry]\n{词::秘密}\n{词::食物}\n";
+    let err = compile(entry, words).err().unwrap().to_string();
+    assert!(
+        err.contains("section [`词::秘密` in file `lib/词.txt`] is private to its file"),
+        "{err}"
+    );
+
+    let entry = "[include \"词.txt\" as 词]\n[entry]\n{物品}\n[物品]\n桶\n[物品]\n锅\n";
+    let err = compile(entry, words).err().unwrap().to_string();
+    assert!(
+        err.contains(
+            "section [`物品` in file `lib/entry.txt`] is defined more than once in the same file"
+        ),
+        "{err}"
+    );
+    // 没有命名空间的文件和包含它的文件共用名字
+    let entry = "[include \"词.txt\"]\n[entry]\n{食物}\n[物品]\n桶\n";
+    let err = compile(entry, words).err().unwrap().to_string();
+    assert!(err.contains("cannot re-define section [`物品`"), "{err}");
+}
+
+#[test]
+fn test_extension() {
+    use super::{compile_from, Compiled, MemorySources};
+
+    let compile = |team: &str| -> Result<Compiled> {
+        let sources: MemorySources = [
+            (
+                "lib/entry.txt",
+                "[include \"team.txt\" as team]\n[entry]\n{物品}和{家具}\n[物品]\n桶\n锅\n\
+                [家具 = 桌椅 | 柜子]\n[桌椅]\n桌子\n椅子\n[柜子]\n衣柜\n书柜\n",
+            ),
+            ("lib/team.txt", team),
+        ]
+        .into_iter()
+        .collect();
+        compile_from(&sources, "lib", &CompileOptions::default())
+    };
+    let rules_of = |compiled: &Compiled, name: &str| {
+        let index = compiled.meta.section_names.iter().position(|n| n == name);
+        compiled.map[index.unwrap()].encoder.len()
+    };
+
+    // 别的文件里的扩充引用自己文件里的私有段落
+    let compiled = compile("[+物品]\n{新词}\n[private 新词]\n杯子\n碗\n[+桌椅]\n凳子\n").unwrap();
+    assert_eq!(rules_of(&compiled, "物品"), 3);
+    assert_eq!(rules_of(&compiled, "家具"), 5);
+    let text = crate::encode(&compiled.map, b"fg2").unwrap();
+    assert_eq!(crate::decode(&compiled.map, &text).unwrap(), b"fg2");
+
+    let err = compile("[+物品]\n{没有}\n").err().unwrap().to_string();
+    assert!(
+        err.contains("section `没有` is not defined, but referenced by section [`team::物品`"),
+        "{err}"
+    );
+    let err = compile("[+物品]\n桶\n").err().unwrap().to_string();
+    assert!(
+        err.contains("word `桶` appears more than once in section [`物品`"),
+        "{err}"
+    );
+    let err = compile("[+柜子]\n椅子\n").err().unwrap().to_string();
+    assert!(
+        err.contains("word `椅子` is in both [`桌椅` in file `lib/entry.txt`] and [`柜子`"),
+        "{err}"
+    );
+    let err = compile("[+家具]\n凳子\n").err().unwrap().to_string();
+    assert!(err.contains("cannot extend union"), "{err}");
+    let err = compile("[+没有]\n凳子\n").err().unwrap().to_string();
+    assert!(
+        err.contains("extends `没有`, which is not defined"),
+        "{err}"
+    );
+    // 出错的段落再定义一次也要报告
+    let err = compile("[+物品]\n碗\n[新词]\n[新词]\n杯子\n")
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.contains("section [`team::新词` in file `lib/team.txt`] is empty"),
+        "{err}"
+    );
+    assert!(
+        err.contains("section [`team::新词` in file `lib/team.txt`] is defined more than once"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_extends() {
+    use super::{compile_from, MemorySources};
+
+    let mut sources = MemorySources::new();
+    sources.insert(
+        "base/entry.txt",
+        "[entry]\n{形容词}的{物品}\n[形容词]\n好看\n难看\n[物品]\n桶\n锅\n[+物品]\n碗\n",
+    );
+    sources.insert(
+        "mine/entry.txt",
+        "[extends \"../base\"]\n[物品]\n杯子\n盘子\n[+形容词]\n普通\n",
+    );
+    let compiled = compile_from(&sources, "mine", &CompileOptions::default()).unwrap();
+
+    // 覆盖的段落连同原库里对它的扩充一起换掉，没覆盖的照样继承
+    assert_eq!(
+        compiled.notes,
+        ["section [`物品` in file `mine/../base/entry.txt`] \
+        is overridden by [`物品` in file `mine/entry.txt`]"]
+    );
+    let rules: Vec<usize> = compiled.map.iter().map(|sec| sec.encoder.len()).collect();
+    assert_eq!(compiled.meta.section_names, ["entry", "形容词", "物品"]);
+    assert_eq!(rules, [1, 3, 2]);
+}
+
+#[test]
+fn test_duplicate_word() {
+    use super::{compile_from, MemorySources};
+
+    let compile = |entry: &str| {
+        let sources: MemorySources = [("lib/entry.txt", entry)].into_iter().collect();
+        let err = compile_from(&sources, "lib", &CompileOptions::default());
+        err.err().unwrap().to_string()
+    };
+
+    let err = compile("[entry]\n茶\n咖啡\n茶\n");
+    assert!(err.contains("word `茶` appears more than once"), "{err}");
+    assert!(!err.contains("normalization"), "{err}");
+
+    // 合成的é和分解的e+◌́写法不同，规范化后才重复
+    let err = compile("[entry]\ncafé\n茶\ncafe\u{301}\n");
+    assert!(err.contains("appears more than once"), "{err}");
+    assert!(err.contains("after Unicode normalization"), "{err}");
+}
+
+#[test]
+fn test_tags() {
+    use super::{compile_from, MemorySources};
+    use crate::syntax::Section;
+
+    let sources: MemorySources = [(
+        "lib/entry.txt",
+        "# 整行注释\n[entry]\n{形容词#正面}的{食物} # 行尾注释\n{形容词}的{好吃}\n[形容词]\n开心 #正面\n\
+        难过 #负面\n{好吃}\n[好吃]\n美味 #正面 #味道\n难吃 #负面 #味道\n[食物]\n饭\n面\n",
+    )]
+    .into_iter()
+    .collect();
+    let compiled = compile_from(&sources, "lib", &CompileOptions::default()).unwrap();
+    let section = |name: &str| -> &Section {
+        let index = compiled.meta.section_names.iter().position(|n| n == name);
+        &compiled.map[index.unwrap()]
+    };
+    let tags = |name: &str| -> Vec<Vec<&str>> {
+        let tags = section(name).tags.iter();
+        tags.map(|t| t.iter().map(ShareStr::as_str).collect())
+            .collect()
+    };
+
+    assert_eq!(tags("形容词"), [vec!["正面"], vec!["负面"], vec![]]);
+    assert_eq!(tags("好吃"), [vec!["正面", "味道"], vec!["负面", "味道"]]);
+    assert!(section("食物").tags.is_empty());
+
+    // 只引用别的段落的规则收窄到那个段落里带标签的词
+    assert_eq!(section("形容词#正面").encoder.len(), 2);
+    assert_eq!(section("好吃#正面").encoder.len(), 1);
+    let text = crate::encode(&compiled.map, b"tag").unwrap();
+    assert_eq!(crate::decode(&compiled.map, &text).unwrap(), b"tag");
+
+    let err = compile_from(
+        &[("lib/entry.txt", "[entry]\n{食物#正面}\n[食物]\n饭\n")]
+            .into_iter()
+            .collect::<MemorySources>(),
+        "lib",
+        &CompileOptions::default(),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+    assert!(
+        err.contains("no word of section [`食物`") && err.contains("is tagged `#正面`"),
+        "{err}"
+    );
+
+    // 紧跟着词的`#`不会悄悄变成标签
+    for source in ["[entry]\n饼#甜\n", "[entry]\n{食物}#甜\n[食物]\n饭\n"] {
+        let err = compile_from(
+            &[("lib/entry.txt", source)]
+                .into_iter()
+                .collect::<MemorySources>(),
+            "lib",
+            &CompileOptions::default(),
+        )
+        .err()
+        .unwrap();
+        let err = format!("{err:#}");
+        assert!(err.contains("`#` right after a word is ambiguous"), "{err}");
+        assert!(err.contains("lib/entry.txt:2:"), "{err}");
     }
 }
@@ -0,0 +1,244 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::Result;
+
+use super::{
+    diagnostic::{Diagnostic, Span},
+    link::{self, LinkedSection, LinkedSeg},
+    parse_tokens::{self, SecInfo, SectionKind},
+    searcher::display_rule,
+    CompileOptions, FileSystem, SourceProvider,
+};
+
+#[derive(Clone)]
+pub struct LintOptions {
+    pub compile: CompileOptions,
+    /// Rules longer than this many characters, as written, are reported.
+    pub max_rule_len: usize,
+    /// A section is reported when one of its rules can write this many times
+    /// as many texts as another.
+    pub max_imbalance: u64,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        LintOptions {
+            compile: CompileOptions::default(),
+            max_rule_len: 40,
+            max_imbalance: 1000,
+        }
+    }
+}
+
+pub fn lint(base_dir: impl AsRef<Path>, options: &LintOptions) -> Result<Vec<Diagnostic>> {
+    lint_from(&FileSystem, base_dir, options)
+}
+
+/// Find what compiles but is probably not meant, such as sections never used.
+/// The library must compile, and the warnings are sorted by location.
+pub fn lint_from(
+    sources: &dyn SourceProvider,
+    base_dir: impl AsRef<Path>,
+    options: &LintOptions,
+) -> Result<Vec<Diagnostic>> {
+    let (expr_secs, _) = parse_tokens::parse(sources, base_dir.as_ref())?;
+    let (expr_secs, _) = link::apply_overrides(expr_secs);
+    // 宏只有用到时才会生成段落，扩充会并入原段落，都不算
+    let defined: Vec<SecInfo> = expr_secs
+        .iter()
+        .filter(|sec| sec.params.is_empty() && !matches!(sec.kind, SectionKind::Extend))
+        .map(|sec| sec.info.clone())
+        .collect();
+    let entry = link::link_secs(expr_secs, &options.compile)?;
+
+    let mut linter = Linter {
+        options,
+        counts: HashMap::new(),
+        used: HashSet::new(),
+        words: HashMap::new(),
+        warnings: Vec::new(),
+    };
+    linter.visit(&entry);
+
+    for info in &defined {
+        if !linter.used.contains(&info.qualified_name()) {
+            linter.warn(
+                info.span.as_ref(),
+                format!("section [{info}] is never used"),
+            );
+        }
+    }
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by(|a, b| {
+        let key = |diag: &Diagnostic| {
+            diag.location
+                .as_ref()
+                .map(|loc| (loc.file.clone(), loc.line, loc.column))
+        };
+        key(a).cmp(&key(b))
+    });
+    Ok(warnings)
+}
+
+struct Linter<'a> {
+    options: &'a LintOptions,
+    /// Number of different texts each section can write.
+    counts: HashMap<*const LinkedSection, u64>,
+    used: HashSet<String>,
+    /// Rules that are plain text, and the section they are first seen in.
+    words: HashMap<String, String>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Linter<'_> {
+    fn warn(&mut self, span: Option<&Span>, message: String) {
+        self.warnings.push(Diagnostic {
+            message,
+            location: span.map(Span::locate),
+        });
+    }
+
+    fn visit(&mut self, sec: &Rc<LinkedSection>) -> u64 {
+        if let Some(&count) = self.counts.get(&Rc::as_ptr(sec)) {
+            return count;
+        }
+        self.used.insert(sec.info.qualified_name());
+
+        let mut rule_counts = Vec::with_capacity(sec.rules.len());
+        for rule in &sec.rules {
+            let mut count = 1u64;
+            for seg in &rule.segs {
+                let n = match seg {
+                    LinkedSeg::Use(inner) => self.visit(inner),
+                    LinkedSeg::Generate(g) => self
+                        .options
+                        .compile
+                        .generators
+                        .get(g)
+                        .map_or(1, |generator| generator.count().into()),
+                    _ => 1,
+                };
+                count = count.saturating_mul(n);
+            }
+            rule_counts.push(count);
+
+            let text = display_rule(&rule.segs);
+            let len = text.chars().count();
+            if len > self.options.max_rule_len {
+                self.warn(
+                    rule.span.as_ref(),
+                    format!(
+                        "rule `{text}` of section [{}] is {len} characters long, longer than {}",
+                        sec.info, self.options.max_rule_len
+                    ),
+                );
+            }
+
+            if rule
+                .segs
+                .iter()
+                .all(|seg| matches!(seg, LinkedSeg::Text(_)))
+            {
+                match self.words.entry(text.clone()) {
+                    Entry::Occupied(first) if *first.get() != sec.info.to_string() => {
+                        let message = format!(
+                            "`{text}` is a rule of both [{}] and [{}]",
+                            first.get(),
+                            sec.info
+                        );
+                        self.warn(rule.span.as_ref(), message);
+                    }
+                    Entry::Occupied(_) => {}
+                    Entry::Vacant(vac) => {
+                        vac.insert(sec.info.to_string());
+                    }
+                }
+            }
+        }
+
+        // 只有一条规则、里面也没有可选的东西，这个段落不携带任何信息
+        if let ([rule], [1]) = (&sec.rules[..], &rule_counts[..]) {
+            self.warn(
+                sec.info.span.as_ref(),
+                format!(
+                    "section [{}] has only one rule and always writes `{}`, it carries no data",
+                    sec.info,
+                    display_rule(&rule.segs)
+                ),
+            );
+        }
+
+        let most = (0..sec.rules.len()).max_by_key(|&i| rule_counts[i]);
+        let least = (0..sec.rules.len()).min_by_key(|&i| rule_counts[i]);
+        if let (Some(most), Some(least)) = (most, least) {
+            if rule_counts[most] / rule_counts[least] >= self.options.max_imbalance {
+                self.warn(
+                    sec.info.span.as_ref(),
+                    format!(
+                        "rules of section [{}] are unbalanced, `{}` can write {} texts but `{}` only {}",
+                        sec.info,
+                        display_rule(&sec.rules[most].segs),
+                        rule_counts[most],
+                        display_rule(&sec.rules[least].segs),
+                        rule_counts[least]
+                    ),
+                );
+            }
+        }
+
+        let count = rule_counts
+            .iter()
+            .fold(0u64, |sum, &n| sum.saturating_add(n));
+        self.counts.insert(Rc::as_ptr(sec), count);
+        count
+    }
+}
+
+#[test]
+fn test_lint() {
+    use super::MemorySources;
+
+    let sources: MemorySources = [
+        (
+            "lib/entry.txt",
+            "[include \"词.txt\"]\n[entry]\n{形容词}的{物品}\n{颜色}{物品}{物品}{物品}\n",
+        ),
+        (
+            "lib/词.txt",
+            "[形容词]\n好看\n[颜色]\n红色\n蓝色\n[物品]\n桶\n杯子\n锅\n碗\n红色\n[没用的]\n空\n",
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let options = LintOptions {
+        max_rule_len: 12,
+        max_imbalance: 50,
+        ..LintOptions::default()
+    };
+    let warnings: Vec<String> = lint_from(&sources, "lib", &options)
+        .unwrap()
+        .into_iter()
+        .map(|diag| diag.message)
+        .collect();
+
+    let expected = [
+        "rule `{颜色}{物品}{物品}{物品}` of section [`entry` in file `lib/entry.txt`] is 16 characters long",
+        "rules of section [`entry` in file `lib/entry.txt`] are unbalanced",
+        "section [`形容词` in file `lib/词.txt`] has only one rule",
+        "`红色` is a rule of both",
+        "section [`没用的` in file `lib/词.txt`] is never used",
+    ];
+    assert_eq!(warnings.len(), expected.len(), "{warnings:#?}");
+    for expected in expected {
+        assert!(
+            warnings.iter().any(|w| w.starts_with(expected)),
+            "{expected} not in {warnings:#?}"
+        );
+    }
+}
@@ -1,16 +1,156 @@
-use std::path::Path;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+
+use crate::{file::Metadata, generator::GeneratorTable};
 
 use super::SerializeMap;
 
+pub use builder::{LibraryBuilder, RulePart};
+pub use diagnostic::{Diagnostic, Diagnostics, Location};
+pub use lint::{lint, lint_from, LintOptions};
+pub use source::{FileSystem, MemorySources, SourceProvider};
+
+mod ambiguity;
+mod builder;
+mod decodability;
+mod diagnostic;
 mod link;
+mod lint;
 mod parse_tokens;
 mod searcher;
 mod serialize;
+mod source;
+mod table;
+mod tracery;
+
+#[derive(Clone, Default)]
+pub struct CompileOptions {
+    /// Sections implemented in Rust, referenced from library files by name.
+    pub generators: GeneratorTable,
+    pub limits: Limits,
+}
+
+/// How much work the compiler may spend on a library before giving up on it,
+/// so that a rule that grows out of hand fails with an error instead of hanging.
+#[derive(Clone)]
+pub struct Limits {
+    /// Most nodes in the decoding table of one section.
+    pub max_table_nodes: usize,
+    /// Most characters the decoder may read ahead to choose a rule.
+    pub max_lookahead: usize,
+    /// Most pairs of readings compared to prove that texts have only one meaning.
+    pub max_search_states: usize,
+    /// Longest time compiling may take, no limit if `None`. Not enforced on
+    /// `wasm32-unknown-unknown`, which has no clock to measure it by.
+    pub max_time: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_table_nodes: 1 << 20,
+            max_lookahead: 256,
+            max_search_states: 1 << 20,
+            max_time: None,
+        }
+    }
+}
+
+/// [`Limits`] of one compile, with the time it has to be done by.
+#[derive(Clone, Copy)]
+struct Budget<'a> {
+    limits: &'a Limits,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Budget<'a> {
+    fn new(limits: &'a Limits) -> Self {
+        Budget {
+            limits,
+            deadline: limits.max_time.and_then(|max| Some(now()? + max)),
+        }
+    }
+
+    /// Fail when compiling has run out of time, `doing` tells what it was busy with.
+    fn check_time(&self, doing: impl FnOnce() -> String) -> Result<()> {
+        match (self.deadline, self.limits.max_time) {
+            (Some(deadline), Some(max)) if now().is_some_and(|now| now > deadline) => Err(anyhow!(
+                "compiling took longer than {max:?}, it was stopped while {}",
+                doing()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The current time, none where `Instant::now` would panic for lack of a clock.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn now() -> Option<Instant> {
+    Some(Instant::now())
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn now() -> Option<Instant> {
+    None
+}
+
+/// A compiled library with what the compiler has to say about its source.
+pub struct Compiled {
+    pub map: SerializeMap,
+    /// What the compiler knows about the library, to be saved with it. The name,
+    /// description and author are left for the caller to fill in.
+    pub meta: Metadata,
+    /// Things worth knowing that do not stop compiling, such as overridden sections.
+    pub notes: Vec<String>,
+}
+
+/// Write a compiled library as a Tracery grammar, see [`tracery::export`]. What cannot
+/// be written is told in `notes`.
+pub fn export_tracery(map: &SerializeMap, notes: &mut Vec<String>) -> Result<String> {
+    tracery::export(map, notes)
+}
 
 pub fn compile(base_dir: impl AsRef<Path>) -> Result<SerializeMap> {
-    let expr_secs = parse_tokens::parse(base_dir)?;
-    let linked_secs = link::link_secs(expr_secs)?;
-    Ok(serialize::serialize(&linked_secs))
+    compile_with(base_dir, &CompileOptions::default())
+}
+
+pub fn compile_with(base_dir: impl AsRef<Path>, options: &CompileOptions) -> Result<SerializeMap> {
+    compile_with_notes(base_dir, options).map(|c| c.map)
+}
+
+pub fn compile_with_notes(
+    base_dir: impl AsRef<Path>,
+    options: &CompileOptions,
+) -> Result<Compiled> {
+    compile_from(&FileSystem, base_dir, options)
+}
+
+/// Compile the library whose `entry.txt` is in `base_dir` of `sources`,
+/// which may be files in memory rather than on disk.
+pub fn compile_from(
+    sources: &dyn SourceProvider,
+    base_dir: impl AsRef<Path>,
+    options: &CompileOptions,
+) -> Result<Compiled> {
+    let hashing = source::Hashing::new(sources, base_dir.as_ref());
+    let (expr_secs, mut notes) = parse_tokens::parse(&hashing, base_dir.as_ref())?;
+    let (expr_secs, override_notes) = link::apply_overrides(expr_secs);
+    notes.extend(override_notes);
+    let linked_secs = link::link_secs(expr_secs, options)?;
+    let (map, section_names) = serialize::serialize_with_names(&linked_secs);
+    Ok(Compiled {
+        map,
+        meta: Metadata {
+            created_by: Some(format!("food-generator2 {}", env!("CARGO_PKG_VERSION"))),
+            source_hash: Some(hashing.finish()),
+            section_names,
+            compression: cfg!(feature = "compression"),
+            ..Metadata::default()
+        },
+        notes,
+    })
 }
@@ -1,101 +1,467 @@
-use std::{fmt::Display, fs::read_to_string, path::Path, rc::Rc};
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use anyhow::{anyhow, Result};
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_while1},
-    character::complete::{multispace0, multispace1},
-    combinator::{cut, eof, flat_map, map, opt, recognize, value, verify},
-    multi::{many0, many1},
-    sequence::{delimited, pair, preceded, terminated},
+    character::complete::{line_ending, multispace0, multispace1, space0},
+    combinator::{cut, eof, map, opt, recognize, value, verify},
+    error::ErrorKind,
+    multi::{many0, many1, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 use unicode_ident::is_xid_continue;
+use unicode_normalization::UnicodeNormalization;
 
 use crate::share_str::ShareStr;
 
-pub(super) type ExprSectionBody = Vec<Vec<ExprSeg>>;
+use super::{
+    diagnostic::{error_at, Diagnostic, Diagnostics, Span},
+    source::SourceProvider,
+    table::{self, TableFormat},
+    tracery,
+};
+
+pub(super) type ExprSectionBody = Vec<ExprRule>;
 
-pub fn parse(base_dir: impl AsRef<Path>) -> Result<Vec<ExprSection>> {
-    SyntaxParser::parse(base_dir).map(|x| x.sections)
+/// Sections of a library, with notes about what was read but ignored.
+pub fn parse(
+    sources: &dyn SourceProvider,
+    base_dir: &Path,
+) -> Result<(Vec<ExprSection>, Vec<String>)> {
+    SyntaxParser::parse(sources, base_dir).map(|x| (x.sections, x.notes))
 }
 
 #[derive(Debug)]
 pub(super) struct ExprSection {
     pub rules: ExprSectionBody,
     pub info: SecInfo,
+    /// Parameters of a macro section, empty for an ordinary one.
+    pub params: Vec<ShareStr>,
+    pub kind: SectionKind,
+    /// 0 for the library being compiled, n for a library it extends through n `[extends]`.
+    pub inherit_depth: u32,
 }
 
 #[derive(Debug)]
+pub(super) enum SectionKind {
+    Rules,
+    /// `[+name]`, appends its rules to the section `name`.
+    Extend,
+    /// `[name = a | b]`, all rules of the listed sections as one choice.
+    Union(Vec<ExprRef>),
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct ExprRule {
+    pub segs: Vec<ExprSeg>,
+    pub tags: Vec<ShareStr>,
+    pub attrs: Vec<(ShareStr, ShareStr)>,
+    /// How often the rule is chosen relative to the others, 1 unless given by a table.
+    pub weight: u32,
+    /// Where the rule is written, none for rules read from tables and grammars.
+    pub span: Option<Span>,
+    /// Written differently before Unicode normalization.
+    pub normalized: bool,
+}
+
+#[derive(Clone, Debug)]
 pub enum ExprSeg {
     Text(ShareStr),
-    Use(ShareStr),
+    Use {
+        target: ExprRef,
+        capture: Option<ShareStr>,
+    },
+    Recall {
+        capture: ShareStr,
+        attr: Option<ShareStr>,
+    },
+    /// Attribute of the word chosen by the latest reference to `section` in the same rule.
+    Attr {
+        section: ShareStr,
+        attr: ShareStr,
+    },
+    /// Text written in quotes, never trimmed.
+    Quoted(ShareStr),
 }
 
-#[derive(Debug)]
-struct SyntaxParser {
+impl ExprSeg {
+    /// The text of the segment that locates it in the source.
+    pub fn source_text(&self) -> &ShareStr {
+        match self {
+            ExprSeg::Text(txt) | ExprSeg::Quoted(txt) => txt,
+            ExprSeg::Use { target, .. } => &target.name,
+            ExprSeg::Recall { capture, .. } => capture,
+            ExprSeg::Attr { section, .. } => section,
+        }
+    }
+}
+
+struct SyntaxParser<'s> {
+    sources: &'s dyn SourceProvider,
     sections: Vec<ExprSection>,
+    /// Every file read so far, keyed by canonical path.
+    included: HashSet<PathBuf>,
+    /// Files being read, outermost first.
+    including: Vec<PathBuf>,
+    inherit_depth: u32,
+    notes: Vec<String>,
+    /// Errors found so far, reading goes on to find more of them.
+    errors: Diagnostics,
+}
+
+/// Set by pragmas, for the sections after them in the same file.
+#[derive(Clone, Copy, Default)]
+struct SectionFlags {
+    /// `[!ignore-case]`, letter case does not matter when decoding.
+    ignore_case: bool,
+    /// `[!preserve-space]`, spaces around rules are part of their text.
+    preserve_space: bool,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 enum SectionHeader<'a> {
-    Inline(&'a str),
-    File(&'a str),
+    Inline {
+        name: &'a str,
+        params: Vec<&'a str>,
+        private: bool,
+        extend: bool,
+    },
+    Union {
+        name: &'a str,
+        parts: Vec<&'a str>,
+        private: bool,
+    },
+    File {
+        path: &'a str,
+        alias: Option<&'a str>,
+    },
+    /// Inherit every section of another library directory.
+    Extends(&'a str),
+    /// Sections of a Tracery grammar, one for each symbol.
+    Tracery {
+        path: &'a str,
+        alias: Option<&'a str>,
+    },
+    /// A section whose rules are read from a spreadsheet or JSON file.
+    Table {
+        format: TableFormat,
+        path: &'a str,
+        options: Vec<(&'a str, &'a str)>,
+        name: &'a str,
+    },
+    /// `[!name]`, changes how the rest of the file is read.
+    Pragma(&'a str),
 }
 
-impl SyntaxParser {
-    fn parse(base_dir: impl AsRef<Path>) -> Result<Self> {
+impl<'s> SyntaxParser<'s> {
+    fn parse(sources: &'s dyn SourceProvider, base_dir: &Path) -> Result<Self> {
         let mut this = SyntaxParser {
+            sources,
             sections: Vec::new(),
+            included: HashSet::new(),
+            including: Vec::new(),
+            inherit_depth: 0,
+            notes: Vec::new(),
+            errors: Diagnostics::default(),
         };
 
-        this.read_file(&base_dir.as_ref().join("entry.txt"))?;
+        this.read_file(&base_dir.join("entry.txt"), &Rc::from(""))?;
+        std::mem::take(&mut this.errors).into_result()?;
         Ok(this)
     }
 
-    #[inline]
-    fn read_file(&mut self, file_path: &Path) -> Result<()> {
-        self.parse_file(file_path, &read_to_string(file_path)?)
+    fn read_file(&mut self, file_path: &Path, scope: &Rc<str>) -> Result<()> {
+        let real_path = self
+            .sources
+            .canonicalize(file_path)
+            .map_err(|err| anyhow!("cannot read file `{}`: {err}", file_path.display()))?;
+
+        if let Some(pos) = self.including.iter().position(|p| *p == real_path) {
+            let cycle: Vec<_> = self.including[pos..]
+                .iter()
+                .chain([&real_path])
+                .map(|p| format!("`{}`", p.display()))
+                .collect();
+            return Err(anyhow!("include cycle found: {}", cycle.join(" -> ")));
+        }
+        if !self.included.insert(real_path.clone()) {
+            return Err(anyhow!(
+                "file `{}` is included more than once",
+                file_path.display()
+            ));
+        }
+
+        let (content, raw) = self.read_source(file_path)?;
+        self.including.push(real_path);
+        let result = self.parse_file(file_path, &content, raw.as_deref(), scope);
+        self.including.pop();
+        result
     }
 
-    fn parse_file(&mut self, file_path: &Path, content: &str) -> Result<()> {
-        let base_dir = file_path.parent().unwrap_or(Path::new(""));
+    /// Content of a file in NFC, with the content as written if normalizing changed it.
+    fn read_source(&self, path: &Path) -> Result<(String, Option<String>)> {
+        let raw = self
+            .sources
+            .read_to_string(path)
+            .map_err(|err| anyhow!("cannot read file `{}`: {err}", path.display()))?;
+        // 解码时输入也会转成NFC，两边才能对上
+        let content: String = raw.nfc().collect();
+        Ok(if content == raw {
+            (raw, None)
+        } else {
+            (content, Some(raw))
+        })
+    }
+
+    fn parse_file(
+        &mut self,
+        file_path: &Path,
+        content: &str,
+        raw: Option<&str>,
+        scope: &Rc<str>,
+    ) -> Result<()> {
         let file_path: Rc<Path> = file_path.to_owned().into_boxed_path().into();
 
-        let (_, clean_code) =
-            remove_comments(&content).map_err(|err| anyhow!("cannot remove comments: {err}"))?;
-        let clean_code = ShareStr::new(&clean_code);
+        // 注释只会删掉行尾，剩下的代码行号列号都不变
+        let clean_code = ShareStr::new(&remove_comments(content));
+        let span = |text: &str| Span::new(file_path.clone(), clean_code.recognize(text).unwrap());
 
-        let mut no_comments_parser = terminated(
-            many0(flat_map(section_header, |header: SectionHeader| {
-                let this = &*self;
-                let cc = &clean_code;
-                map(
-                    move |s| this.parse_section(cc, s, header),
-                    move |body| (header, body),
-                )
-            })),
-            eof,
-        );
-
-        let (_, sections): (_, Vec<(SectionHeader<'_>, Option<ExprSectionBody>)>) =
-            no_comments_parser(&clean_code).map_err(|err| anyhow!("parse error: {err}"))?;
-
-        drop(no_comments_parser);
-
-        for (header, section_body) in sections {
-            match header {
-                SectionHeader::File(path) => self.read_file(&base_dir.join(path))?,
-                SectionHeader::Inline(name) => {
+        let mut flags = SectionFlags::default();
+        let mut rest: &str = &clean_code;
+        // 一段一段地解析和读取，编译指令改变的标志对后面段落的解析也有效
+        while !rest.is_empty() {
+            let parsed = section_header(rest).and_then(|(s, header)| {
+                let (s, body) =
+                    self.parse_section(&clean_code, &file_path, s, &header, flags.preserve_space)?;
+                Ok((s, (header, body)))
+            });
+            let (header, mut section_body) = match parsed {
+                Ok((s, parsed)) => {
+                    rest = s;
+                    parsed
+                }
+                Err(nom::Err::Error(_)) => {
+                    self.errors.push(
+                        Diagnostic {
+                            message: "expected a rule or a section header".to_owned(),
+                            location: Some(span(rest).locate()),
+                        }
+                        .into(),
+                    );
+                    return Ok(());
+                }
+                Err(nom::Err::Failure(err)) => {
+                    let message = match (err.code, err.input.chars().next()) {
+                        (ErrorKind::Eof, _) => "expected a rule or a section header".to_owned(),
+                        (ErrorKind::Not, Some('#')) => "`#` right after a word is ambiguous, \
+                            write `词 #标签` for a tag or `词 # 注释` for a comment"
+                            .to_owned(),
+                        (_, None | Some('\r' | '\n')) => "unexpected end of line".to_owned(),
+                        (_, Some(ch)) => format!("unexpected `{ch}`"),
+                    };
+                    self.errors.push(
+                        Diagnostic {
+                            message,
+                            location: Some(span(err.input).locate()),
+                        }
+                        .into(),
+                    );
+                    return Ok(());
+                }
+                Err(err) => return Err(anyhow!("parse error: {err}")),
+            };
+
+            if let (Some(raw), Some(rules)) = (raw, section_body.as_mut()) {
+                mark_normalized(rules, Some(&clean_code), raw);
+            }
+            let result = self.read_section(
+                header,
+                section_body,
+                &file_path,
+                scope,
+                &clean_code,
+                &mut flags,
+            );
+            if let Err(err) = result {
+                self.errors.push(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_section(
+        &mut self,
+        header: SectionHeader,
+        section_body: Option<ExprSectionBody>,
+        file_path: &Rc<Path>,
+        scope: &Rc<str>,
+        clean_code: &ShareStr,
+        flags: &mut SectionFlags,
+    ) -> Result<()> {
+        let base_dir = file_path.parent().unwrap_or(Path::new(""));
+        let span = |text: &str| Span::new(file_path.clone(), clean_code.recognize(text).unwrap());
+        let ignore_case = flags.ignore_case;
+        match header {
+            SectionHeader::Pragma("ignore-case") => flags.ignore_case = true,
+            SectionHeader::Pragma("preserve-space") => flags.preserve_space = true,
+            SectionHeader::Pragma(other) => {
+                return Err(error_at(
+                    Some(&span(other)),
+                    anyhow!("unknown pragma `{other}`"),
+                ))
+            }
+            SectionHeader::File { path, alias } => {
+                let scope = match alias {
+                    Some(alias) => Rc::from(join_scope(scope, alias)),
+                    None => scope.clone(),
+                };
+                let files = expand_include(self.sources, base_dir, path)
+                    .map_err(|err| error_at(Some(&span(path)), err))?;
+                for file in files {
+                    if let Err(err) = self.read_file(&file, &scope) {
+                        self.errors.push(error_at(Some(&span(path)), err));
+                    }
+                }
+            }
+            SectionHeader::Table {
+                format,
+                path,
+                options,
+                name,
+            } => {
+                let table_path = base_dir.join(path);
+                let rules = self
+                    .read_source(&table_path)
+                    .and_then(|(content, raw)| {
+                        let mut rules = table::read_table(format, &table_path, &content, &options)?;
+                        if let Some(raw) = raw {
+                            mark_normalized(&mut rules, None, &raw);
+                        }
+                        Ok(rules)
+                    })
+                    .map_err(|err| error_at(Some(&span(path)), err))?;
+                self.sections.push(ExprSection {
+                    rules,
+                    info: SecInfo {
+                        name: clean_code.recognize(name).unwrap(),
+                        file: table_path.into(),
+                        scope: scope.clone(),
+                        private: false,
+                        ignore_case,
+                        span: Some(span(name)),
+                    },
+                    params: Vec::new(),
+                    kind: SectionKind::Rules,
+                    inherit_depth: self.inherit_depth,
+                });
+            }
+            SectionHeader::Tracery { path, alias } => {
+                let scope: Rc<str> = match alias {
+                    Some(alias) => Rc::from(join_scope(scope, alias)),
+                    None => scope.clone(),
+                };
+                let grammar_path: Rc<Path> = base_dir.join(path).into();
+                let symbols = self
+                    .read_source(&grammar_path)
+                    .and_then(|(content, raw)| {
+                        let mut symbols =
+                            tracery::import(&grammar_path, &content, &mut self.notes)?;
+                        if let Some(raw) = raw {
+                            for (_, rules) in &mut symbols {
+                                mark_normalized(rules, None, &raw);
+                            }
+                        }
+                        Ok(symbols)
+                    })
+                    .map_err(|err| error_at(Some(&span(path)), err))?;
+                for (symbol, rules) in symbols {
                     self.sections.push(ExprSection {
+                        rules,
                         info: SecInfo {
-                            name: clean_code.recognize(name).unwrap(),
-                            file: file_path.clone(),
+                            name: ShareStr::new(&symbol),
+                            file: grammar_path.clone(),
+                            scope: scope.clone(),
+                            private: false,
+                            ignore_case,
+                            span: Some(span(path)),
                         },
-                        rules: section_body.unwrap(),
+                        params: Vec::new(),
+                        kind: SectionKind::Rules,
+                        inherit_depth: self.inherit_depth,
                     });
                 }
             }
+            SectionHeader::Extends(path) => {
+                self.inherit_depth += 1;
+                let result = self.read_file(&base_dir.join(path).join("entry.txt"), scope);
+                self.inherit_depth -= 1;
+                result.map_err(|err| error_at(Some(&span(path)), err))?
+            }
+            SectionHeader::Inline {
+                name,
+                params,
+                private,
+                extend,
+            } => {
+                self.sections.push(ExprSection {
+                    info: SecInfo {
+                        name: clean_code.recognize(name).unwrap(),
+                        file: file_path.clone(),
+                        scope: scope.clone(),
+                        private,
+                        ignore_case,
+                        span: Some(span(name)),
+                    },
+                    params: params
+                        .into_iter()
+                        .map(|p| clean_code.recognize(p).unwrap())
+                        .collect(),
+                    rules: section_body.unwrap(),
+                    kind: if extend {
+                        SectionKind::Extend
+                    } else {
+                        SectionKind::Rules
+                    },
+                    inherit_depth: self.inherit_depth,
+                });
+            }
+            SectionHeader::Union {
+                name,
+                parts,
+                private,
+            } => {
+                self.sections.push(ExprSection {
+                    info: SecInfo {
+                        name: clean_code.recognize(name).unwrap(),
+                        file: file_path.clone(),
+                        scope: scope.clone(),
+                        private,
+                        ignore_case,
+                        span: Some(span(name)),
+                    },
+                    params: Vec::new(),
+                    rules: Vec::new(),
+                    kind: SectionKind::Union(
+                        parts
+                            .into_iter()
+                            .map(|p| ExprRef {
+                                name: clean_code.recognize(p).unwrap(),
+                                args: Vec::new(),
+                                filter: Vec::new(),
+                            })
+                            .collect(),
+                    ),
+                    inherit_depth: self.inherit_depth,
+                });
+            }
         }
 
         Ok(())
@@ -104,37 +470,137 @@ impl SyntaxParser {
     fn parse_section<'a>(
         &self,
         origin: &ShareStr,
+        file: &Rc<Path>,
         s: &'a str,
-        header: SectionHeader,
+        header: &SectionHeader,
+        preserve_space: bool,
     ) -> IResult<&'a str, Option<ExprSectionBody>> {
         match header {
-            SectionHeader::File(_) => Ok((s, None)),
-            SectionHeader::Inline(_) => {
+            SectionHeader::File { .. }
+            | SectionHeader::Extends(_)
+            | SectionHeader::Table { .. }
+            | SectionHeader::Tracery { .. }
+            | SectionHeader::Pragma(_)
+            | SectionHeader::Union { .. } => Ok((s, None)),
+            SectionHeader::Inline { .. } => {
                 let match_seg = alt((
                     delimited(
                         tag("{"),
-                        map(cut(section_name), |id| {
-                            ExprSeg::Use(origin.recognize(id).unwrap())
-                        }),
+                        cut(alt((
+                            map(
+                                pair(
+                                    preceded(tag("$"), section_name),
+                                    opt(preceded(tag("."), section_name)),
+                                ),
+                                |(id, attr)| ExprSeg::Recall {
+                                    capture: origin.recognize(id).unwrap(),
+                                    attr: attr.map(|a| origin.recognize(a).unwrap()),
+                                },
+                            ),
+                            map(
+                                separated_pair(section_name, tag("."), section_name),
+                                |(id, attr)| ExprSeg::Attr {
+                                    section: origin.recognize(id).unwrap(),
+                                    attr: origin.recognize(attr).unwrap(),
+                                },
+                            ),
+                            map(
+                                pair(
+                                    |s| section_ref(origin, s),
+                                    opt(preceded(tag(":"), section_name)),
+                                ),
+                                |(target, capture)| ExprSeg::Use {
+                                    target,
+                                    capture: capture.map(|c| origin.recognize(c).unwrap()),
+                                },
+                            ),
+                        ))),
                         cut(tag("}")),
                     ),
-                    map(is_not("{[\r\n"), |txt: &str| {
+                    map(string_expr, |txt: &str| {
+                        ExprSeg::Quoted(origin.recognize(txt).unwrap())
+                    }),
+                    map(is_not("{[|\"#\r\n"), |txt: &str| {
                         ExprSeg::Text(origin.recognize(txt).unwrap())
                     }),
                 ));
 
+                let attribute = preceded(
+                    tuple((space0, tag("|"), space0)),
+                    cut(separated_pair(
+                        section_name,
+                        tuple((space0, tag("="), space0)),
+                        verify(
+                            map(is_not("|\r\n"), |v: &str| {
+                                string_trim_end(&origin.recognize(v).unwrap())
+                            }),
+                            |v: &ShareStr| !v.is_empty(),
+                        ),
+                    )),
+                );
+
+                let rule_tag = preceded(pair(space0, tag("#")), cut(section_name));
+
+                // 紧跟着文字的`#`分不清是标签还是旧式的注释，要求用空白隔开
+                let mut words = many1(match_seg);
+                let words = move |s| {
+                    let (rest, segs) = words(s)?;
+                    let spaced = matches!(
+                        segs.last(),
+                        Some(ExprSeg::Text(txt)) if txt.as_str().ends_with(char::is_whitespace)
+                    );
+                    if rest.starts_with('#') && !spaced {
+                        return Err(nom::Err::Failure(nom::error::Error::new(
+                            rest,
+                            ErrorKind::Not,
+                        )));
+                    }
+                    Ok((rest, segs))
+                };
+
+                // 保留空白时只有换行用来分隔规则
+                let rule_end: fn(&str) -> IResult<&str, ()> = if preserve_space {
+                    end_lines
+                } else {
+                    end_spaces
+                };
+
                 map(
                     many0(terminated(
-                        map(many1(match_seg), |mut vec| {
-                            if let Some(ExprSeg::Text(first)) = vec.first_mut() {
-                                *first = string_trim_start(first);
-                            }
-                            if let Some(ExprSeg::Text(last)) = vec.last_mut() {
-                                *last = string_trim_end(last);
-                            }
-                            vec
-                        }),
-                        end_spaces,
+                        map(
+                            tuple((words, many0(rule_tag), many0(attribute))),
+                            |(mut segs, tags, attrs)| {
+                                if !preserve_space {
+                                    if let Some(ExprSeg::Text(first)) = segs.first_mut() {
+                                        *first = string_trim_start(first);
+                                    }
+                                }
+                                // 标签和属性前面的空白只是分隔
+                                if !preserve_space || !tags.is_empty() || !attrs.is_empty() {
+                                    if let Some(ExprSeg::Text(last)) = segs.last_mut() {
+                                        *last = string_trim_end(last);
+                                    }
+                                }
+                                ExprRule {
+                                    span: Some(Span::new(
+                                        file.clone(),
+                                        segs[0].source_text().clone(),
+                                    )),
+                                    segs,
+                                    tags: tags
+                                        .into_iter()
+                                        .map(|t| origin.recognize(t).unwrap())
+                                        .collect(),
+                                    attrs: attrs
+                                        .into_iter()
+                                        .map(|(k, v)| (origin.recognize(k).unwrap(), v))
+                                        .collect(),
+                                    weight: 1,
+                                    normalized: false,
+                                }
+                            },
+                        ),
+                        rule_end,
                     )),
                     Some,
                 )(s)
@@ -143,43 +609,283 @@ impl SyntaxParser {
     }
 }
 
+/// Mark the rules written differently before Unicode normalization, those with a text
+/// not found as written in `raw`, the file before normalizing. Rules parsed from `code`
+/// are looked for in their own line, other rules anywhere in the file.
+fn mark_normalized(rules: &mut [ExprRule], code: Option<&ShareStr>, raw: &str) {
+    // 规范化和删注释都不会增减换行，代码和原文的行一一对应
+    let line_starts: Vec<usize> = code.map_or_else(Vec::new, |code| {
+        std::iter::once(0)
+            .chain(code.match_indices('\n').map(|(i, _)| i + 1))
+            .collect()
+    });
+    let raw_lines: Vec<&str> = raw.split('\n').collect();
+
+    for rule in rules {
+        let written = match rule.segs.first() {
+            Some(first) if !line_starts.is_empty() => {
+                let offset = first.source_text().offset();
+                let line = line_starts.partition_point(|&start| start <= offset) - 1;
+                raw_lines.get(line).copied().unwrap_or(raw)
+            }
+            _ => raw,
+        };
+        rule.normalized = rule
+            .segs
+            .iter()
+            .any(|seg| !written.contains(seg.source_text().as_str()));
+    }
+}
+
+/// Files referred to by an include path, which may be a glob pattern or a directory.
+/// The result is sorted so that the compiled library does not depend on the file system.
+fn expand_include(
+    sources: &dyn SourceProvider,
+    base_dir: &Path,
+    path: &str,
+) -> Result<Vec<PathBuf>> {
+    let full_path = base_dir.join(path);
+    let is_pattern = |s: &str| s.contains(['*', '?', '[']);
+
+    let mut files = if is_pattern(path) {
+        // 从第一个带通配符的部分开始匹配，前面的部分当作目录
+        let mut root = base_dir.to_owned();
+        let mut components = Path::new(path).components().peekable();
+        while let Some(comp) = components.next_if(|c| !is_pattern(&c.as_os_str().to_string_lossy()))
+        {
+            root.push(comp);
+        }
+        let rest: PathBuf = components.collect();
+        let pattern = glob::Pattern::new(&rest.to_string_lossy())
+            .map_err(|err| anyhow!("bad include pattern `{path}`: {err}"))?;
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..glob::MatchOptions::new()
+        };
+
+        let mut found = Vec::new();
+        if sources.is_dir(&root) {
+            walk_dir(sources, &root, Path::new(""), &mut found)?;
+        }
+        found
+            .into_iter()
+            .filter(|(_, relative)| pattern.matches_path_with(relative, options))
+            .map(|(file, _)| file)
+            .collect()
+    } else if sources.is_dir(&full_path) {
+        let mut files = Vec::new();
+        for entry in sources.read_dir(&full_path)? {
+            let p = full_path.join(entry.file_name().unwrap_or_default());
+            if !sources.is_dir(&p) && p.extension().is_some_and(|ext| ext == "txt") {
+                files.push(p);
+            }
+        }
+        files
+    } else {
+        return Ok(vec![full_path]);
+    };
+
+    if files.is_empty() {
+        return Err(anyhow!(
+            "include `{path}` in `{}` matches no file",
+            base_dir.display()
+        ));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Every file under `dir`, with its path relative to where the walk starts.
+fn walk_dir(
+    sources: &dyn SourceProvider,
+    dir: &Path,
+    relative: &Path,
+    found: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    for entry in sources.read_dir(dir)? {
+        let name = entry.file_name().unwrap_or_default();
+        let (path, relative) = (dir.join(name), relative.join(name));
+        if sources.is_dir(&path) {
+            walk_dir(sources, &path, &relative, found)?;
+        } else {
+            found.push((path, relative));
+        }
+    }
+    Ok(())
+}
+
 fn section_name(s: &str) -> IResult<&str, &str> {
     recognize(take_while1(is_xid_continue))(s)
 }
 
+fn section_path(s: &str) -> IResult<&str, &str> {
+    recognize(separated_list1(tag("::"), section_name))(s)
+}
+
+fn section_ref<'a>(origin: &ShareStr, s: &'a str) -> IResult<&'a str, ExprRef> {
+    map(
+        tuple((
+            section_path,
+            opt(delimited(
+                pair(tag("("), space0),
+                separated_list1(tuple((space0, tag(","), space0)), |s| {
+                    section_ref(origin, s)
+                }),
+                pair(space0, cut(tag(")"))),
+            )),
+            many0(preceded(tag("#"), cut(section_name))),
+        )),
+        |(name, args, filter)| ExprRef {
+            name: origin.recognize(name).unwrap(),
+            args: args.unwrap_or_default(),
+            filter: filter
+                .into_iter()
+                .map(|t| origin.recognize(t).unwrap())
+                .collect(),
+        },
+    )(s)
+}
+
 fn string_expr(s: &str) -> IResult<&str, &str> {
     delimited(tag("\""), is_not("\"\r\n"), tag("\""))(s)
 }
 
-fn section_header(s: &str) -> IResult<&str, SectionHeader> {
+fn section_header(s: &str) -> IResult<&str, SectionHeader<'_>> {
     delimited(
         pair(multispace0, tag("[")),
         alt((
             map(
-                preceded(pair(tag("include"), multispace1), string_expr),
-                SectionHeader::File,
+                preceded(
+                    tag("!"),
+                    take_while1(|ch: char| ch.is_alphanumeric() || ch == '-'),
+                ),
+                SectionHeader::Pragma,
+            ),
+            map(
+                preceded(
+                    pair(tag("include"), multispace1),
+                    tuple((
+                        terminated(
+                            alt((
+                                value(TableFormat::Csv, tag("csv")),
+                                value(TableFormat::Tsv, tag("tsv")),
+                                value(TableFormat::Json, tag("json")),
+                            )),
+                            multispace1,
+                        ),
+                        string_expr,
+                        many0(preceded(
+                            multispace1,
+                            separated_pair(section_name, tag("="), string_expr),
+                        )),
+                        preceded(tuple((multispace1, tag("as"), multispace1)), section_name),
+                    )),
+                ),
+                |(format, path, options, name)| SectionHeader::Table {
+                    format,
+                    path,
+                    options,
+                    name,
+                },
+            ),
+            map(
+                preceded(
+                    pair(tag("include"), multispace1),
+                    pair(
+                        string_expr,
+                        opt(preceded(
+                            tuple((multispace1, tag("as"), multispace1)),
+                            section_name,
+                        )),
+                    ),
+                ),
+                |(path, alias)| SectionHeader::File { path, alias },
+            ),
+            map(
+                preceded(
+                    tuple((tag("include"), multispace1, tag("tracery"), multispace1)),
+                    pair(
+                        string_expr,
+                        opt(preceded(
+                            tuple((multispace1, tag("as"), multispace1)),
+                            section_name,
+                        )),
+                    ),
+                ),
+                |(path, alias)| SectionHeader::Tracery { path, alias },
+            ),
+            map(
+                preceded(pair(tag("extends"), multispace1), string_expr),
+                SectionHeader::Extends,
+            ),
+            map(
+                tuple((
+                    opt(terminated(tag("private"), multispace1)),
+                    section_name,
+                    tuple((space0, tag("="), space0)),
+                    separated_list1(tuple((space0, tag("|"), space0)), section_path),
+                )),
+                |(private, name, _, parts)| SectionHeader::Union {
+                    name,
+                    parts,
+                    private: private.is_some(),
+                },
             ),
             map(
-                take_while1(|ch: char| !ch.is_whitespace() && ch != ']'),
-                SectionHeader::Inline,
+                tuple((
+                    opt(terminated(tag("private"), multispace1)),
+                    opt(tag("+")),
+                    take_while1(|ch: char| !ch.is_whitespace() && !"]()".contains(ch)),
+                    opt(delimited(
+                        pair(tag("("), space0),
+                        separated_list1(tuple((space0, tag(","), space0)), section_name),
+                        pair(space0, tag(")")),
+                    )),
+                )),
+                |(private, extend, name, params)| SectionHeader::Inline {
+                    name,
+                    params: params.unwrap_or_default(),
+                    private: private.is_some(),
+                    extend: extend.is_some(),
+                },
             ),
         )),
         pair(tag("]"), end_spaces),
     )(s)
 }
 
-fn remove_comments(s: &str) -> IResult<&str, String> {
-    map(
-        terminated(
-            many0(alt((
-                recognize(string_expr),
-                is_not("#\""),
-                value("", pair(tag("#"), opt(is_not("\r\n")))),
-            ))),
-            eof,
-        ),
-        |vec| vec.join(""),
-    )(s)
+/// `#` starts a comment at the start of a line or when followed by a space,
+/// otherwise it marks a tag like `开心 #正面` or a filter like `{形容词#正面}`.
+fn remove_comments(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+
+    for line in s.split_inclusive('\n') {
+        let mut rest = line;
+        let mut line_start = true;
+        while let Some(pos) = rest.find(['#', '"']) {
+            let (before, after) = rest.split_at(pos);
+            output.push_str(before);
+            line_start &= before.trim().is_empty();
+
+            if let Ok((after_quote, _)) = recognize(string_expr)(after) {
+                output.push_str(&after[..after.len() - after_quote.len()]);
+                rest = after_quote;
+            } else if let Some(after_quote) = after.strip_prefix('"') {
+                output.push('"');
+                rest = after_quote;
+            } else if line_start || !after[1..].starts_with(|ch: char| !ch.is_whitespace()) {
+                rest = &after[after.find(['\r', '\n']).unwrap_or(after.len())..];
+                break;
+            } else {
+                output.push('#');
+                rest = &after[1..];
+            }
+            line_start = false;
+        }
+        output.push_str(rest);
+    }
+
+    output
 }
 
 fn end_spaces(s: &str) -> IResult<&str, ()> {
@@ -192,6 +898,13 @@ fn end_spaces(s: &str) -> IResult<&str, ()> {
     ))(s)
 }
 
+fn end_lines(s: &str) -> IResult<&str, ()> {
+    alt((
+        value((), many1(preceded(space0, line_ending))),
+        value((), pair(multispace0, eof)),
+    ))(s)
+}
+
 fn string_trim_start(s: &ShareStr) -> ShareStr {
     let skip_len = s.len() - s.trim_start().len();
     s.clone_range(skip_len..)
@@ -202,14 +915,203 @@ fn string_trim_end(s: &ShareStr) -> ShareStr {
     s.clone_range(..reserve_len)
 }
 
+/// A reference to a section, or to a macro section together with its arguments.
+#[derive(Clone, Debug)]
+pub struct ExprRef {
+    pub name: ShareStr,
+    pub args: Vec<ExprRef>,
+    /// Tags the chosen word must have, like `{形容词#正面}`.
+    pub filter: Vec<ShareStr>,
+}
+
+impl Display for ExprRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some((first, rest)) = self.args.split_first() {
+            write!(f, "({first}")?;
+            for arg in rest {
+                write!(f, ",{arg}")?;
+            }
+            write!(f, ")")?;
+        }
+        for tag in &self.filter {
+            write!(f, "#{tag}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SecInfo {
     pub name: ShareStr,
     pub file: Rc<Path>,
+    /// Namespace given by `[include "..." as ns]`, empty at the top level.
+    pub scope: Rc<str>,
+    /// Only visible to sections of the same file.
+    pub private: bool,
+    /// Set by `[!ignore-case]`, letter case does not matter when decoding.
+    pub ignore_case: bool,
+    /// Where the section is defined, or the header that brings it in.
+    pub span: Option<Span>,
+}
+
+impl SecInfo {
+    pub fn qualified_name(&self) -> String {
+        join_scope(&self.scope, &self.name)
+    }
 }
 
 impl Display for SecInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "`{}` in file `{}`", self.name, self.file.display())
+        write!(
+            f,
+            "`{}` in file `{}`",
+            self.qualified_name(),
+            self.file.display()
+        )
+    }
+}
+
+pub fn join_scope(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{scope}::{name}")
     }
 }
+
+#[test]
+fn test_section_header() {
+    let (_, header) = section_header("[include \"形容词.txt\" as adj]\n").unwrap();
+    assert!(matches!(
+        header,
+        SectionHeader::File {
+            path: "形容词.txt",
+            alias: Some("adj")
+        }
+    ));
+
+    let (_, header) = section_header("[private 普通形容词]\n").unwrap();
+    assert!(matches!(
+        header,
+        SectionHeader::Inline {
+            name: "普通形容词",
+            private: true,
+            ..
+        }
+    ));
+
+    let (_, header) = section_header("[物品 = 家电 | adj::厨具]\n").unwrap();
+    assert!(matches!(
+        header,
+        SectionHeader::Union { name: "物品", parts, .. } if parts == ["家电", "adj::厨具"]
+    ));
+
+    let (_, header) = section_header("[+物品]\n").unwrap();
+    assert!(matches!(
+        header,
+        SectionHeader::Inline {
+            name: "物品",
+            extend: true,
+            ..
+        }
+    ));
+
+    let (rest, path) = section_path("adj::普通形容词}").unwrap();
+    assert_eq!((rest, path), ("}", "adj::普通形容词"));
+}
+
+#[test]
+fn test_remove_comments() {
+    assert_eq!(
+        remove_comments("#正面\n开心 #正面 # 注释\n{形容词#正面}\"#\"# 注释\n"),
+        "\n开心 #正面 \n{形容词#正面}\"#\"\n"
+    );
+}
+
+#[test]
+fn test_include() {
+    use super::source::MemorySources;
+
+    let parse_names = |entry: &str| -> Result<Vec<String>> {
+        let mut sources: MemorySources = [
+            ("lib/词/乙.txt", "[乙]\n二\n"),
+            ("lib/词/甲.txt", "[甲]\n一\n"),
+            ("lib/词/表.csv", "三\n"),
+            ("lib/词/更多/丁.txt", "[丁]\n四\n"),
+            ("lib/环/一.txt", "[include \"二.txt\"]\n"),
+            ("lib/环/二.txt", "[include \"一.txt\"]\n"),
+        ]
+        .into_iter()
+        .collect();
+        sources.insert("lib/entry.txt", format!("{entry}[entry]\n零\n"));
+        let (sections, _) = parse(&sources, Path::new("lib"))?;
+        Ok(sections
+            .iter()
+            .map(|sec| sec.info.qualified_name())
+            .collect())
+    };
+
+    // 通配符不跨目录，目录只读里面的txt文件，不往下找
+    assert_eq!(
+        parse_names("[include \"词/*.txt\"]\n").unwrap(),
+        ["乙", "甲", "entry"]
+    );
+    assert_eq!(
+        parse_names("[include \"词/**/*.txt\" as 词]\n").unwrap(),
+        ["词::乙", "词::丁", "词::甲", "entry"]
+    );
+    assert_eq!(
+        parse_names("[include \"词\"]\n").unwrap(),
+        ["乙", "甲", "entry"]
+    );
+
+    let err = parse_names("[include \"词/*.json\"]\n").unwrap_err();
+    assert!(err.to_string().contains("matches no file"), "{err}");
+    let err = parse_names("[include \"环/一.txt\"]\n").unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("include cycle found: `lib/环/一.txt` -> `lib/环/二.txt` -> `lib/环/一.txt`"),
+        "{err}"
+    );
+    let err = parse_names("[include \"词/*.txt\"]\n[include \"词/./甲.txt\"]\n").unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("file `lib/词/./甲.txt` is included more than once"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_whitespace() {
+    use super::{compile_from, source::MemorySources, CompileOptions};
+
+    let sources: MemorySources = [
+        (
+            "lib/entry.txt",
+            "[include \"en.txt\"]\n[entry]\n{adj}\" \"{noun}{end}\n",
+        ),
+        (
+            "lib/en.txt",
+            "[!ignore-case]\n[adj]\nbig\nred\n[noun]\ncat\ndog\n\
+            [!preserve-space]\n[end]\n, then \n. \n",
+        ),
+    ]
+    .into_iter()
+    .collect();
+    let map = compile_from(&sources, "lib", &CompileOptions::default())
+        .unwrap()
+        .map;
+
+    let decode = |text: &str| crate::decode(&map, text);
+    assert!(decode("big cat. ").is_ok());
+    assert!(decode("red dog, then big cat. ").is_ok());
+    // 引号和保留空白写下的空格都要读到
+    assert!(decode("big cat.").is_err());
+    assert!(decode("bigcat. ").is_err());
+    assert!(decode("Red Dog, Then BIG CAT. ").is_ok());
+
+    let text = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(decode(&text).unwrap(), b"fg2");
+    assert_eq!(decode(&text.to_uppercase()).unwrap(), b"fg2");
+}
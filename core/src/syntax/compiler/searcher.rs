@@ -1,215 +1,423 @@
 use std::{
-    collections::{
-        hash_map::{Entry, VacantEntry},
-        HashMap,
-    },
-    fmt::Write as _,
+    collections::{BTreeMap, HashMap},
     rc::Rc,
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Error, Result};
 
-use crate::share_str::ShareStr;
+use crate::{share_str::ShareStr, syntax::fold_case};
 
-use super::{link::LinkedSeg, parse_tokens::SecInfo};
+use super::{
+    diagnostic::error_at,
+    link::{LinkedRule, LinkedSection, LinkedSeg},
+    parse_tokens::SecInfo,
+    Budget,
+};
 
-type Table = HashMap<char, Rc<Trie>>;
+/// Ordered, so that the table is built and written the same way every time.
+type Table = BTreeMap<char, Rc<Trie>>;
 
 #[derive(Clone)]
 pub enum SearchSeg {
-    Text(ShareStr),
+    /// Text, folded by [`fold_case`] when its section ignores case.
+    Text {
+        text: ShareStr,
+        folded: bool,
+    },
+    /// A whole text of a section, not yet looked into.
+    Section(Rc<LinkedSection>),
     Use(Rc<Trie>),
+    /// Text only known while encoding, such as the output of a generator.
+    Opaque(Rc<str>),
+}
+
+/// Segments left to read, shared by every branch they are copied into.
+pub type Rest = Option<Rc<RestNode>>;
+
+pub struct RestNode {
+    seg: SearchSeg,
+    next: Rest,
+}
+
+fn cons(seg: SearchSeg, next: Rest) -> Rest {
+    Some(Rc::new(RestNode { seg, next }))
 }
 
-#[derive(Clone)]
 pub enum Trie {
     Branch(Table),
     Leaf {
         value: u32,
-        rest_nodes: Vec<SearchSeg>, // reversed
+        rest: Rest,
+    },
+    /// Every rule left starts with the same section, which is read as a whole before
+    /// looking further.
+    Through {
+        section: Rc<LinkedSection>,
+        next: Rc<Trie>,
     },
+    /// Rules that cannot be told apart by looking ahead, the decoder has to search.
+    Search,
+    /// What a key of a [`Trie::Branch`] leads to when the key was folded from the text
+    /// of a section that ignores case.
+    Folded(Rc<Trie>),
 }
 
-impl Trie {
-    fn new() -> Self {
-        Trie::Branch(HashMap::new())
+/// Build the decision tree of a section, or [`Trie::Search`] when a rule is a prefix
+/// of another or cannot be told apart by looking ahead. Whether such a section can
+/// still be decoded is proven by `decodability::check`. Fails only when the tree
+/// outgrows the limits of `budget`.
+pub fn compile(rules: &[LinkedRule], info: &SecInfo, budget: Budget) -> Result<Trie> {
+    let start = rules
+        .iter()
+        .zip(0u32..)
+        .map(|(rule, value)| {
+            let mut rest = None;
+            for seg in rule.segs.iter().rev() {
+                let seg = match seg {
+                    LinkedSeg::Text(t) if info.ignore_case => {
+                        let folded: String = t.chars().map(fold_case).collect();
+                        SearchSeg::Text {
+                            text: ShareStr::new(&folded),
+                            folded: true,
+                        }
+                    }
+                    LinkedSeg::Text(t) => SearchSeg::Text {
+                        text: t.clone(),
+                        folded: false,
+                    },
+                    LinkedSeg::Use(u) if matches!(*u.search, Trie::Search) => {
+                        SearchSeg::Opaque(display_seg(seg).into())
+                    }
+                    LinkedSeg::Use(u) => SearchSeg::Section(u.clone()),
+                    LinkedSeg::Generate(_) | LinkedSeg::Recall { .. } | LinkedSeg::Attr { .. } => {
+                        SearchSeg::Opaque(display_seg(seg).into())
+                    }
+                };
+                rest = cons(seg, rest);
+            }
+            (value, rest)
+        })
+        .collect();
+
+    let mut builder = Builder {
+        rules,
+        info,
+        budget,
+        nodes: 0,
+        rule_nodes: vec![0; rules.len()],
+        exceeded: None,
+    };
+    match (builder.build(start, 0), builder.exceeded) {
+        (_, Some(err)) => Err(err),
+        (Ok(trie), None) => Ok(trie),
+        (Err(_), None) => Ok(Trie::Search),
     }
+}
 
-    fn insert(&mut self, expect: char) -> Result<InsertResult> {
-        let table = match self {
-            Self::Branch(b) => b,
-            Self::Leaf { .. } => self.expand_to_table()?,
-        };
+struct Builder<'a> {
+    rules: &'a [LinkedRule],
+    info: &'a SecInfo,
+    budget: Budget<'a>,
+    nodes: usize,
+    /// Nodes each rule has been copied into.
+    rule_nodes: Vec<usize>,
+    /// A limit that was hit, which fails the compile instead of falling back to searching.
+    exceeded: Option<Error>,
+}
 
-        let r = match table.entry(expect) {
-            Entry::Occupied(occ) => InsertResult::Occupied(Rc::get_mut(occ.into_mut()).unwrap()),
-            Entry::Vacant(vac) => InsertResult::Vacant(vac),
+impl Builder<'_> {
+    /// Split `rules` by what they read next. Every rule is only copied into the branches
+    /// of its own first characters, and a section that all rules start with is read as
+    /// a whole instead of character by character, so the trie grows with the size of
+    /// the rules rather than with the number of texts they can write.
+    fn build(&mut self, mut rules: Vec<(u32, Rest)>, depth: usize) -> Result<Trie> {
+        self.count(&rules, depth)?;
+        match &mut rules[..] {
+            [] => return Ok(Trie::Branch(Table::new())),
+            [(value, rest)] => {
+                return Ok(Trie::Leaf {
+                    value: *value,
+                    rest: rest.take(),
+                })
+            }
+            _ => {}
+        }
+
+        for (_, rest) in &mut rules {
+            *rest = skip_empty(rest.take())?;
+        }
+
+        // 都从同一个段落开始的话，整个读完这个段落再往下分
+        let first_section = |(_, rest): &(u32, Rest)| match rest.as_deref() {
+            Some(RestNode {
+                seg: SearchSeg::Section(s),
+                ..
+            }) if s.deterministic => Some(s.clone()),
+            _ => None,
         };
+        if let Some(section) = first_section(&rules[0]) {
+            if rules[1..]
+                .iter()
+                .all(|rule| first_section(rule).is_some_and(|s| Rc::ptr_eq(&s, &section)))
+            {
+                let rules = rules
+                    .into_iter()
+                    .map(|(value, rest)| (value, rest.and_then(|node| node.next.clone())))
+                    .collect();
+                return Ok(Trie::Through {
+                    section,
+                    next: self.build(rules, depth)?.into(),
+                });
+            }
+        }
+
+        let mut groups: BTreeMap<char, (bool, Vec<(u32, Rest)>)> = BTreeMap::new();
+        for (value, rest) in rules {
+            for (ch, folded, rest) in first_chars(rest)? {
+                // 有一条规则不区分大小写就让各种大小写都进来，读规则时再按各自的段落核对
+                let (any_folded, group) = groups.entry(ch).or_default();
+                *any_folded |= folded;
+                group.push((value, rest));
+            }
+        }
 
-        Ok(r)
+        let mut table = Table::new();
+        for (ch, (folded, group)) in groups {
+            let trie = self.build(group, depth + 1)?;
+            let trie = if folded {
+                Trie::Folded(trie.into())
+            } else {
+                trie
+            };
+            table.insert(ch, trie.into());
+        }
+        Ok(Trie::Branch(table))
     }
 
-    fn expand_to_table(&mut self) -> Result<&mut Table> {
-        let this = std::mem::replace(self, Self::new());
-        let (
-            Self::Leaf {
-                value,
-                rest_nodes: mut org_rest_nodes,
-            },
-            Self::Branch(new_table),
-        ) = (this, self)
-        else {
-            panic!("self must be a leaf");
+    /// Count a new node for `rules`, `depth` characters from the start.
+    fn count(&mut self, rules: &[(u32, Rest)], depth: usize) -> Result<()> {
+        self.nodes += 1;
+        for &(value, _) in rules {
+            self.rule_nodes[value as usize] += 1;
+        }
+
+        let limits = self.budget.limits;
+        let err = if self.nodes > limits.max_table_nodes {
+            Some(self.too_many_nodes())
+        } else if let [(first, _), (second, _), ..] = rules {
+            (depth > limits.max_lookahead).then(|| {
+                let rule = &self.rules[*first as usize];
+                error_at(
+                    rule.span.as_ref(),
+                    anyhow!(
+                        "rules `{}` and `{}` of section [{}] read the same for more than {} \
+                        characters, too far for the decoder to look ahead, \
+                        its decoding table may grow to {} nodes",
+                        display_rule(&rule.segs),
+                        display_rule(&self.rules[*second as usize].segs),
+                        self.info,
+                        limits.max_lookahead,
+                        self.estimate().1
+                    ),
+                )
+            })
+        } else {
+            None
+        };
+        let err = match err {
+            Some(err) => Some(err),
+            None if self.nodes.is_multiple_of(4096) => self
+                .budget
+                .check_time(|| {
+                    format!(
+                        "building the decoding table of section [{}], which may grow to {} nodes",
+                        self.info,
+                        self.estimate().1
+                    )
+                })
+                .err(),
+            None => None,
         };
 
-        loop {
-            match org_rest_nodes.pop() {
-                Some(SearchSeg::Text(txt)) => {
-                    let Some((org_ch, rest)) = split_first(&txt) else {
-                        continue;
-                    };
-
-                    org_rest_nodes.push(SearchSeg::Text(rest));
-                    new_table.insert(
-                        org_ch,
-                        Trie::Leaf {
-                            value,
-                            rest_nodes: org_rest_nodes,
-                        }
-                        .into(),
-                    );
-                    break;
-                }
-                Some(SearchSeg::Use(trie)) => match &*trie {
-                    Trie::Branch(b) => {
-                        for (&key, use_trie) in b.iter() {
-                            let mut rn = org_rest_nodes.clone();
-                            rn.push(SearchSeg::Use(use_trie.clone()));
-                            new_table.insert(
-                                key,
-                                Trie::Leaf {
-                                    value,
-                                    rest_nodes: rn,
-                                }
-                                .into(),
-                            );
-                        }
-                        break;
-                    }
-                    Trie::Leaf { rest_nodes, .. } => {
-                        for seg in rest_nodes.iter() {
-                            org_rest_nodes.push(seg.clone());
-                        }
-                    }
-                },
-                None => {
-                    return Err(anyhow!("this rule contains other rule"));
-                }
+        match err {
+            Some(err) => {
+                self.exceeded = Some(err);
+                Err(anyhow!("a limit of the compiler is exceeded"))
             }
+            None => Ok(()),
         }
+    }
 
-        Ok(new_table)
+    /// Texts each rule can write with their characters, and how many nodes the table
+    /// may grow to.
+    fn estimate(&self) -> (Vec<(u64, u64)>, String) {
+        let mut counts = HashMap::new();
+        let sizes: Vec<(u64, u64)> = self
+            .rules
+            .iter()
+            .map(|rule| count_texts(&rule.segs, &mut counts))
+            .collect();
+        let nodes = sizes
+            .iter()
+            .fold(0u64, |sum, &(_, len)| sum.saturating_add(len));
+        (sizes, display_count(nodes))
+    }
+
+    fn too_many_nodes(&self) -> Error {
+        let (sizes, nodes) = self.estimate();
+        let worst = (0..self.rules.len())
+            .max_by_key(|&i| self.rule_nodes[i])
+            .unwrap_or_default();
+        let message = format!(
+            "the decoding table of section [{}] has more than {} nodes and may grow to {} nodes",
+            self.info, self.budget.limits.max_table_nodes, nodes,
+        );
+
+        // 只是词多的话没有哪条规则该怪
+        let rule = &self.rules[worst];
+        match sizes[worst].0 {
+            texts if texts > 1 => error_at(
+                rule.span.as_ref(),
+                anyhow!(
+                    "{message}, mostly for rule `{}`, which can write {} texts",
+                    display_rule(&rule.segs),
+                    display_count(texts)
+                ),
+            ),
+            _ => error_at(
+                self.info.span.as_ref(),
+                anyhow!("{message}, one for each character of its words"),
+            ),
+        }
     }
 }
 
-enum InsertResult<'a> {
-    Occupied(&'a mut Trie),
-    Vacant(VacantEntry<'a, char, Rc<Trie>>),
+/// A count that may be too large to read digit by digit, or to count at all.
+fn display_count(n: u64) -> String {
+    match n {
+        u64::MAX => format!("more than {:.1e}", n as f64),
+        0..=999_999 => n.to_string(),
+        _ => format!("{:.1e}", n as f64),
+    }
 }
 
-pub fn compile(rules: &Vec<Vec<LinkedSeg>>, info: &SecInfo) -> Result<Trie> {
-    let mut trie = Trie::new();
-    let mut footprint = String::new();
+/// Number of different texts a rule can write, and how many characters they have
+/// together, which is as large as a table that spells them all out can be.
+/// Generators count as a single empty text.
+fn count_texts(
+    segs: &[LinkedSeg],
+    counts: &mut HashMap<*const LinkedSection, (u64, u64)>,
+) -> (u64, u64) {
+    segs.iter().fold((1, 0), |(texts, chars), seg| {
+        let (n, len) = match seg {
+            LinkedSeg::Text(t) => (1, t.chars().count() as u64),
+            LinkedSeg::Use(inner) => match counts.get(&Rc::as_ptr(inner)) {
+                Some(&known) => known,
+                None => {
+                    let sum = inner
+                        .rules
+                        .iter()
+                        .map(|rule| count_texts(&rule.segs, counts))
+                        .fold((0u64, 0u64), |(a, b), (n, len)| {
+                            (a.saturating_add(n), b.saturating_add(len))
+                        });
+                    counts.insert(Rc::as_ptr(inner), sum);
+                    sum
+                }
+            },
+            _ => (1, 0),
+        };
+        // 每个前面的文本都接上每个这里的文本
+        (
+            texts.saturating_mul(n),
+            chars
+                .saturating_mul(n)
+                .saturating_add(len.saturating_mul(texts)),
+        )
+    })
+}
 
-    for (rule, value) in rules.iter().zip(0u32..) {
-        let mut buffer = Vec::new();
-        for seg in rule.iter().rev() {
-            match seg {
-                LinkedSeg::Text(t) => buffer.push(SearchSeg::Text(t.clone())),
-                LinkedSeg::Use(u) => buffer.push(SearchSeg::Use(u.search.clone())),
-            }
-        }
+/// Drop what reads nothing from the front of `rest`, and open up tries that do not
+/// branch right away.
+fn skip_empty(mut rest: Rest) -> Result<Rest> {
+    loop {
+        let Some(node) = rest.as_deref() else {
+            return Err(anyhow!("this rule is contained by other rule"));
+        };
 
-        let display_rule = display_rule(rule);
+        rest = match &node.seg {
+            SearchSeg::Text { text, .. } if text.is_empty() => node.next.clone(),
+            SearchSeg::Use(trie) => match &**trie {
+                Trie::Leaf { rest: inner, .. } => concat(inner, node.next.clone()),
+                Trie::Through { section, next } => cons(
+                    SearchSeg::Section(section.clone()),
+                    cons(SearchSeg::Use(next.clone()), node.next.clone()),
+                ),
+                Trie::Search => unreachable!("searched sections are opaque"),
+                Trie::Folded(_) => unreachable!("folded tries are only kept in tables"),
+                Trie::Branch(_) => return Ok(rest),
+            },
+            _ => return Ok(rest),
+        };
+    }
+}
 
-        compile_rule(&mut trie, buffer, &mut footprint, value).map_err(|err| {
-            anyhow!(
-                "section [{info}] decode tree build failed.\n\
-                error msg: {err}\n\
-                when building `{display_rule}`\n\
-                at `{footprint}`"
-            )
-        })?;
+/// Each character `rest` may start with, whether it was folded, and what is left
+/// to read after it.
+fn first_chars(rest: Rest) -> Result<Vec<(char, bool, Rest)>> {
+    let mut rest = rest;
+    loop {
+        let Some(node) = rest.as_deref() else {
+            return Err(anyhow!("this rule is contained by other rule"));
+        };
 
-        footprint.clear();
-    }
-    Ok(trie)
-}
-
-fn compile_rule(
-    mut trie: &mut Trie,
-    mut buffer: Vec<SearchSeg>,
-    footprint: &mut String,
-    value: u32,
-) -> Result<()> {
-    while let Some(seg) = buffer.pop() {
-        match seg {
-            SearchSeg::Text(txt) => {
-                let mut chars = txt.chars();
-                for ch in &mut chars {
-                    footprint.push(ch);
-                    match trie.insert(ch)? {
-                        InsertResult::Occupied(occ) => trie = occ,
-                        InsertResult::Vacant(vac) => {
-                            buffer.push(SearchSeg::Text(txt.recognize(chars.as_str()).unwrap()));
-                            vac.insert(
-                                Trie::Leaf {
-                                    value,
-                                    rest_nodes: Vec::from_iter(buffer.drain(..)),
-                                }
-                                .into(),
-                            );
-                            return Ok(());
-                        }
-                    }
+        match &node.seg {
+            &SearchSeg::Text { ref text, folded } => {
+                if let Some((ch, tail)) = split_first(text) {
+                    let tail = SearchSeg::Text { text: tail, folded };
+                    return Ok(vec![(ch, folded, cons(tail, node.next.clone()))]);
                 }
+                rest = node.next.clone();
             }
-            SearchSeg::Use(u) => match &*u {
-                Trie::Leaf { rest_nodes, .. } => {
-                    buffer.extend(rest_nodes.iter().cloned().rev());
-                    continue;
-                }
+            SearchSeg::Section(section) => {
+                rest = cons(SearchSeg::Use(section.search.clone()), node.next.clone());
+            }
+            SearchSeg::Use(trie) => match &**trie {
                 Trie::Branch(b) => {
-                    let truncate_len = footprint.len();
-                    for (&key, content) in b.iter() {
-                        let mut buffer2 = buffer.clone();
-                        buffer2.push(SearchSeg::Use(content.clone()));
-
-                        footprint.push(key);
-                        match trie.insert(key)? {
-                            InsertResult::Occupied(occ) => {
-                                compile_rule(occ, buffer2, footprint, value)?
-                            }
-                            InsertResult::Vacant(vac) => {
-                                vac.insert(
-                                    Trie::Leaf {
-                                        value,
-                                        rest_nodes: buffer2,
-                                    }
-                                    .into(),
-                                );
-                            }
-                        }
-
-                        footprint.truncate(truncate_len);
-                    }
-                    return Ok(());
+                    return Ok(b
+                        .iter()
+                        .map(|(&ch, inner)| {
+                            let (folded, inner) = match &**inner {
+                                Trie::Folded(inner) => (true, inner),
+                                _ => (false, inner),
+                            };
+                            let rest = cons(SearchSeg::Use(inner.clone()), node.next.clone());
+                            (ch, folded, rest)
+                        })
+                        .collect())
                 }
+                _ => rest = skip_empty(rest)?,
             },
+            SearchSeg::Opaque(desc) => return Err(opaque_lookahead_error(desc)),
         }
     }
+}
+
+/// `front` followed by `back`, copying only the nodes of `front`.
+fn concat(front: &Rest, back: Rest) -> Rest {
+    let mut segs = Vec::new();
+    let mut node = front.as_deref();
+    while let Some(n) = node {
+        segs.push(n.seg.clone());
+        node = n.next.as_deref();
+    }
+    segs.into_iter()
+        .rev()
+        .fold(back, |rest, seg| cons(seg, rest))
+}
 
-    Err(anyhow!("this rule is contained by other rule"))
+fn opaque_lookahead_error(desc: &str) -> anyhow::Error {
+    anyhow!("cannot tell rules apart by looking into `{desc}`")
 }
 
 fn split_first(s: &ShareStr) -> Option<(char, ShareStr)> {
@@ -218,15 +426,141 @@ fn split_first(s: &ShareStr) -> Option<(char, ShareStr)> {
     Some((ch, rest))
 }
 
-fn display_rule(rule: &Vec<LinkedSeg>) -> String {
-    let mut string = String::new();
-    for seg in rule {
-        match seg {
-            LinkedSeg::Text(t) => string += t.as_str(),
-            LinkedSeg::Use(u) => {
-                write!(&mut string, "{{{}}}", u.info.name).unwrap();
-            }
-        }
+pub fn display_rule(rule: &[LinkedSeg]) -> String {
+    rule.iter().map(display_seg).collect()
+}
+
+fn display_seg(seg: &LinkedSeg) -> String {
+    match seg {
+        LinkedSeg::Text(t) => t.to_string(),
+        LinkedSeg::Use(u) => format!("{{{}}}", u.info.name),
+        LinkedSeg::Generate(g) => format!("{{{g}}}"),
+        LinkedSeg::Recall { name, .. } => format!("{{${name}}}"),
+        LinkedSeg::Attr { name, .. } => format!("{{{name}}}"),
+    }
+}
+
+#[test]
+fn test_lookahead_order() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let sources: MemorySources = [(
+        "lib/entry.txt",
+        "[中间]\n戊\n己\n[前缀]\n丙{中间}丁\n[entry]\n{前缀}子\n{前缀}丑\n",
+    )]
+    .into_iter()
+    .collect();
+    let map = compile_from(&sources, "lib", &CompileOptions::default())
+        .unwrap()
+        .map;
+
+    // 两条规则要读过只有一条规则的[前缀]才能分开，前瞻时它的片段不能倒过来
+    assert!(crate::decode(&map, "丙戊丁丑丙己丁子").is_ok());
+    let text = crate::encode(&map, b"hi").unwrap();
+    assert_eq!(crate::decode(&map, &text).unwrap(), b"hi");
+}
+
+#[test]
+fn test_through() {
+    use super::{builder::RulePart, LibraryBuilder};
+    use crate::syntax::Layer;
+
+    // 两条规则只有读完整个短语才能分开，不用把短语的每种写法都展开
+    let mut builder = LibraryBuilder::new();
+    let sec = |name: &str| RulePart::Section(name.into());
+    let text = |t: &str| RulePart::Text(t.into());
+    builder
+        .add_rule("entry", [text("「"), sec("短语"), text("」了")])
+        .add_rule("entry", [text("「"), sec("短语"), text("」吗")])
+        .add_rule("短语", [sec("形容词"), sec("名词")]);
+    for word in ["好看", "好吃", "难看"] {
+        builder.add_word("形容词", word);
+    }
+    for word in ["锅", "碗", "盘子"] {
+        builder.add_word("名词", word);
     }
-    string
+
+    let map = builder.build().unwrap();
+    let Layer::Branch(root) = &map[0].decoder else {
+        panic!("{:?}", map[0].decoder);
+    };
+    assert!(matches!(root[&'「'], Layer::Through { .. }), "{root:?}");
+
+    let text = crate::encode(&map, b"fg2").unwrap();
+    assert_eq!(crate::decode(&map, &text).unwrap(), b"fg2");
+    assert!(crate::decode(&map, "「好看盘子」呢").is_err());
+}
+
+#[test]
+fn test_limits() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let compile = |entry: &str, options: &CompileOptions| {
+        let sources = MemorySources::from_iter([(
+            "lib/entry.txt",
+            format!("[entry]\n{entry}\n[物品]\n桶\n锅\n碗\n杯子\n盘子\n[容器]\n桶\n锅\n碗\n杯子\n盘子\n"),
+        )]);
+        compile_from(&sources, "lib", options).map(|_| ())
+    };
+    let entry = "{物品}{物品}的\n{容器}了";
+    assert!(compile(entry, &CompileOptions::default()).is_ok());
+
+    // 读完第一个词还分不开，第一条规则要接着展开第二个物品
+    let mut options = CompileOptions::default();
+    options.limits.max_table_nodes = 20;
+    let err = compile(entry, &options).err().unwrap().to_string();
+    assert!(err.contains("has more than 20 nodes"), "{err}");
+    assert!(
+        err.contains("`{物品}{物品}的`, which can write 25 texts"),
+        "{err}"
+    );
+
+    let mut options = CompileOptions::default();
+    options.limits.max_lookahead = 3;
+    let err = compile("一二三四五\n一二三四六", &options)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.contains("read the same for more than 3 characters"),
+        "{err}"
+    );
+    assert!(
+        err.contains("its decoding table may grow to 10 nodes"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_fold_case() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let compile = |entry: &str, included: &str| {
+        let sources: MemorySources = [
+            ("lib/entry.txt", format!("[include \"词.txt\"]\n{entry}")),
+            ("lib/词.txt", included.to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        compile_from(&sources, "lib", &CompileOptions::default())
+            .unwrap()
+            .map
+    };
+
+    // 前瞻读到的字属于不区分大小写的段落，即使当前段落区分大小写
+    let map = compile(
+        "[entry]\n{animal}!\ndog?\n",
+        "[!ignore-case]\n[animal]\ncat\ncow\n",
+    );
+    assert!(crate::decode(&map, "CAT!").is_ok());
+    assert!(crate::decode(&map, "cat!").is_ok());
+    assert!(crate::decode(&map, "DOG?").is_err());
+
+    let map = compile(
+        "[!ignore-case]\n[entry]\n{name}说\nHi\n",
+        "[name]\nAnn\nBob\n",
+    );
+    assert!(crate::decode(&map, "HI").is_ok());
+    assert!(crate::decode(&map, "Ann说").is_ok());
+    assert!(crate::decode(&map, "ann说").is_err());
 }
@@ -1,9 +1,6 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
 
-use crate::{
-    share_str::ShareStr,
-    syntax::{Layer, Section, Seg},
-};
+use crate::syntax::{Layer, Section, Seg};
 
 use super::{
     link::{LinkedSection, LinkedSeg},
@@ -12,19 +9,29 @@ use super::{
 };
 
 pub fn serialize(root_section: &LinkedSection) -> SerializeMap {
+    serialize_with_names(root_section).0
+}
+
+/// The map, with the qualified name of each of its sections.
+pub fn serialize_with_names(root_section: &LinkedSection) -> (SerializeMap, Vec<String>) {
     assert_eq!(root_section.info.name.as_str(), "entry");
     let mut map = HashMap::new();
     let mut vec = Vec::new();
     serailize_sec(&mut map, &mut vec, root_section);
-    vec.into_iter().map(Option::unwrap).collect()
+
+    let mut names = vec![String::new(); vec.len()];
+    for (name, index) in map {
+        names[index as usize] = name;
+    }
+    (vec.into_iter().map(Option::unwrap).collect(), names)
 }
 
 fn serailize_sec(
-    name2index: &mut HashMap<ShareStr, u32>,
+    name2index: &mut HashMap<String, u32>,
     vec: &mut Vec<Option<Section>>,
     sec: &LinkedSection,
 ) -> u32 {
-    let insert_index = match name2index.entry(sec.info.name.clone()) {
+    let insert_index = match name2index.entry(sec.info.qualified_name()) {
         Entry::Occupied(occ) => {
             return *occ.get();
         }
@@ -40,16 +47,23 @@ fn serailize_sec(
         .rules
         .iter()
         .map(|rule| {
-            rule.iter()
+            rule.segs
+                .iter()
                 .map(|seg| match seg {
                     LinkedSeg::Text(t) => Seg::Text(t.clone()),
                     LinkedSeg::Use(sec) => {
-                        let index = match name2index.get(&sec.info.name) {
+                        let index = match name2index.get(&sec.info.qualified_name()) {
                             Some(&index) => index,
                             None => serailize_sec(name2index, vec, sec),
                         };
                         Seg::Use(index)
                     }
+                    LinkedSeg::Generate(name) => Seg::Generate(name.clone()),
+                    &LinkedSeg::Recall { seg, .. } => Seg::Recall(seg),
+                    LinkedSeg::Attr { seg, values, .. } => Seg::Attr {
+                        seg: *seg,
+                        values: values.clone(),
+                    },
                 })
                 .collect()
         })
@@ -57,20 +71,62 @@ fn serailize_sec(
 
     vec[insert_index as usize] = Some(Section {
         encoder: rules,
-        decoder: serialize_trie(&sec.search),
+        decoder: serialize_trie(name2index, vec, &sec.search),
+        ignore_case: sec.info.ignore_case,
+        tags: if sec.rules.iter().all(|rule| rule.tags.is_empty()) {
+            Vec::new()
+        } else {
+            sec.rules.iter().map(|rule| rule.tags.clone()).collect()
+        },
+        weights: if sec.rules.iter().all(|rule| rule.weight == 1) {
+            Vec::new()
+        } else {
+            sec.rules.iter().map(|rule| rule.weight).collect()
+        },
     });
     insert_index
 }
 
-fn serialize_trie(trie: &Trie) -> Layer {
+fn serialize_trie(
+    name2index: &mut HashMap<String, u32>,
+    vec: &mut Vec<Option<Section>>,
+    trie: &Trie,
+) -> Layer {
     match trie {
         Trie::Branch(b) => {
-            let mut map = HashMap::new();
+            let mut map = BTreeMap::new();
             for (&key, content) in b {
-                map.insert(key, serialize_trie(&content));
+                map.insert(key, serialize_trie(name2index, vec, content));
             }
             Layer::Branch(map)
         }
         &Trie::Leaf { value, .. } => Layer::Certain(value),
+        Trie::Through { section, next } => Layer::Through {
+            section: serailize_sec(name2index, vec, section),
+            next: serialize_trie(name2index, vec, next).into(),
+        },
+        Trie::Search => Layer::Search,
+        Trie::Folded(inner) => Layer::Folded(serialize_trie(name2index, vec, inner).into()),
     }
 }
+
+#[test]
+fn test_reproducible() {
+    use super::{compile_from, CompileOptions, MemorySources};
+    use crate::file::{content_hash, save_lib};
+
+    let sources = MemorySources::from_iter([(
+        "lib/entry.txt",
+        "[entry]\n{物品}在{地点}\n[物品]\n桶\n杯子\n锅\n碗\n盘子\n勺子\n筷子\n刀\n叉\n[地点]\n厨房\n客厅\n卧室\n阳台\n",
+    )]);
+    let compile = || {
+        compile_from(&sources, "lib", &CompileOptions::default())
+            .unwrap()
+            .map
+    };
+
+    // 每次编译的哈希表顺序都不一样，写出来的文件要一样
+    let (first, second) = (compile(), compile());
+    assert_eq!(save_lib(&first), save_lib(&second));
+    assert_eq!(content_hash(&first), content_hash(&second));
+}
@@ -0,0 +1,308 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    hash::Hasher,
+    ops::Bound,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::fnv::Fnv64;
+
+/// Where the compiler reads library files from.
+pub trait SourceProvider {
+    fn read_to_string(&self, path: &Path) -> Result<String>;
+
+    /// Files and directories directly inside the directory `path`.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// The same path for every way of writing it, used to tell whether two includes
+    /// refer to the same file. It fails if the file does not exist.
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf>;
+}
+
+/// Files on disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileSystem;
+
+impl SourceProvider for FileSystem {
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        Ok(fs::read_to_string(path)?)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        fs::read_dir(path)?.map(|entry| Ok(entry?.path())).collect()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        Ok(fs::canonicalize(path)?)
+    }
+}
+
+/// Files kept in memory, keyed by path such as `library/entry.txt`.
+/// Directories are implied by the paths of the files in them.
+/// Files kept in memory, keyed by path such as `library/entry.txt`.
+/// Directories are implied by the paths of the files in them.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySources {
+    /// Keyed by normalized path, so that looking up a file is a single search.
+    files: BTreeMap<PathBuf, String>,
+}
+
+impl MemorySources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file, returning the content it replaces.
+    pub fn insert(&mut self, path: impl AsRef<Path>, content: impl Into<String>) -> Option<String> {
+        self.files.insert(normalize(path.as_ref()), content.into())
+    }
+
+    /// Files and directories somewhere inside `dir`, in the order of their paths.
+    fn descendants<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = &'a Path> + 'a {
+        // 路径按部件比较，一个目录下的所有路径排在一起，紧跟在目录自己后面
+        self.files
+            .range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+            .map(|(key, _)| key.as_path())
+            .take_while(move |key| key.starts_with(dir))
+    }
+}
+
+impl<P: AsRef<Path>, S: Into<String>> FromIterator<(P, S)> for MemorySources {
+    fn from_iter<I: IntoIterator<Item = (P, S)>>(iter: I) -> Self {
+        let mut sources = MemorySources::new();
+        for (path, content) in iter {
+            sources.insert(path, content);
+        }
+        sources
+    }
+}
+
+impl From<HashMap<PathBuf, String>> for MemorySources {
+    fn from(files: HashMap<PathBuf, String>) -> Self {
+        files.into_iter().collect()
+    }
+}
+
+impl SourceProvider for MemorySources {
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| anyhow!("no such file"))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let dir = normalize(path);
+        let mut entries: Vec<PathBuf> = self
+            .descendants(&dir)
+            .filter_map(|key| {
+                let first = key.strip_prefix(&dir).ok()?.components().next()?;
+                Some(dir.join(first))
+            })
+            .collect();
+        entries.dedup();
+        if entries.is_empty() {
+            return Err(anyhow!("no such directory"));
+        }
+        Ok(entries)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.descendants(&normalize(path)).next().is_some()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        let path = normalize(path);
+        if self.files.contains_key(&path) {
+            Ok(path)
+        } else {
+            Err(anyhow!("no such file"))
+        }
+    }
+}
+
+/// Files in memory keyed by path, handy for a few files built in code. A file is found
+/// directly when the path asked for is written like its key, otherwise every key is
+/// compared, so many files are better kept in [`MemorySources`].
+impl SourceProvider for HashMap<PathBuf, String> {
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        self.get(path)
+            .or_else(|| {
+                let path = normalize(path);
+                self.iter()
+                    .find(|(key, _)| normalize(key) == path)
+                    .map(|(_, content)| content)
+            })
+            .cloned()
+            .ok_or_else(|| anyhow!("no such file"))
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let dir = normalize(path);
+        let entries: BTreeSet<PathBuf> = self
+            .keys()
+            .filter_map(|key| {
+                let rest = normalize(key).strip_prefix(&dir).ok()?.to_owned();
+                let first = rest.components().next()?;
+                Some(dir.join(first))
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(anyhow!("no such directory"));
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        let dir = normalize(path);
+        self.keys().any(|key| {
+            let key = normalize(key);
+            key != dir && key.starts_with(&dir)
+        })
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        let path = normalize(path);
+        if self.contains_key(&path) || self.keys().any(|key| normalize(key) == path) {
+            Ok(path)
+        } else {
+            Err(anyhow!("no such file"))
+        }
+    }
+}
+
+/// Reads from another provider and hashes every file read, so that a compiled library
+/// can tell which sources it came from.
+pub(super) struct Hashing<'a> {
+    inner: &'a dyn SourceProvider,
+    base_dir: PathBuf,
+    /// Hash of each file read, keyed by its path inside `base_dir` with `/` between parts.
+    files: RefCell<BTreeMap<String, u64>>,
+}
+
+impl<'a> Hashing<'a> {
+    pub fn new(inner: &'a dyn SourceProvider, base_dir: &Path) -> Self {
+        Hashing {
+            inner,
+            base_dir: normalize(base_dir),
+            files: RefCell::default(),
+        }
+    }
+
+    /// Hash of the files read so far, the same whatever order they were read in
+    /// and whichever system they are on.
+    pub fn finish(&self) -> u64 {
+        let mut hasher = Fnv64::default();
+        for (path, hash) in self.files.borrow().iter() {
+            hasher.write(path.as_bytes());
+            hasher.write(&[0]);
+            hasher.write(&hash.to_le_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+impl SourceProvider for Hashing<'_> {
+    fn read_to_string(&self, path: &Path) -> Result<String> {
+        let content = self.inner.read_to_string(path)?;
+        let path = normalize(path);
+        let relative = path.strip_prefix(&self.base_dir).unwrap_or(&path);
+        let key: Vec<_> = relative
+            .components()
+            .map(|comp| comp.as_os_str().to_string_lossy())
+            .collect();
+
+        let mut hasher = Fnv64::default();
+        hasher.write(content.as_bytes());
+        self.files
+            .borrow_mut()
+            .insert(key.join("/"), hasher.finish());
+        Ok(content)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.inner.read_dir(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
+        self.inner.canonicalize(path)
+    }
+}
+
+/// Remove `.` and resolve `..` without touching any file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut output = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir if output.file_name().is_some() => {
+                output.pop();
+            }
+            other => output.push(other),
+        }
+    }
+    output
+}
+
+#[test]
+fn test_memory_sources() {
+    use super::{compile_from, CompileOptions};
+
+    let files = [
+        (
+            "lib/entry.txt",
+            "[include \"词/*.txt\"]\n[entry]\n{形容词}的{物品}\n",
+        ),
+        ("lib/词/形容词.txt", "[形容词]\n红色\n蓝色\n"),
+        ("lib/词/物品.txt", "[物品]\n桶\n杯子\n锅\n碗\n"),
+    ];
+    let sources: MemorySources = files.into_iter().collect();
+
+    // 查找用的路径可以写得和插入时不一样
+    assert!(sources
+        .read_to_string(Path::new("lib/词/../entry.txt"))
+        .is_ok());
+    assert!(sources.is_dir(Path::new("./lib/词")));
+    assert!(!sources.is_dir(Path::new("lib/词/物品.txt")));
+    assert_eq!(
+        sources.read_dir(Path::new("lib")).unwrap(),
+        [PathBuf::from("lib/entry.txt"), PathBuf::from("lib/词")]
+    );
+
+    let map = compile_from(&sources, "lib", &CompileOptions::default())
+        .unwrap()
+        .map;
+    let text = crate::encode(&map, b"hi").unwrap();
+    assert_eq!(crate::decode(&map, &text).unwrap(), b"hi");
+
+    // 直接用HashMap也一样
+    let hash_map: HashMap<PathBuf, String> = files
+        .into_iter()
+        .map(|(path, content)| (path.into(), content.into()))
+        .collect();
+    assert!(hash_map
+        .read_to_string(Path::new("lib/词/../entry.txt"))
+        .is_ok());
+    assert_eq!(
+        hash_map.read_dir(Path::new("lib")).unwrap(),
+        sources.read_dir(Path::new("lib")).unwrap()
+    );
+    let from_hash_map = compile_from(&hash_map, "lib", &CompileOptions::default())
+        .unwrap()
+        .map;
+    assert_eq!(crate::decode(&from_hash_map, &text).unwrap(), b"hi");
+}
@@ -0,0 +1,311 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::share_str::ShareStr;
+
+use super::parse_tokens::{ExprRule, ExprSectionBody, ExprSeg};
+
+#[derive(Clone, Copy, Debug)]
+pub enum TableFormat {
+    Csv,
+    Tsv,
+    Json,
+}
+
+/// Which columns of a table make up the rules of a section.
+#[derive(Default)]
+struct Columns<'a> {
+    /// Words of the section, the first column of a spreadsheet by default.
+    word: Option<&'a str>,
+    weight: Option<&'a str>,
+    tags: Option<&'a str>,
+}
+
+/// Read the rules of `[include csv "food.csv" column="名称" as 食物]` and the like.
+pub fn read_table(
+    format: TableFormat,
+    path: &Path,
+    content: &str,
+    options: &[(&str, &str)],
+) -> Result<ExprSectionBody> {
+    let mut columns = Columns::default();
+    for &(key, value) in options {
+        let slot = match key {
+            "column" => &mut columns.word,
+            "weight" => &mut columns.weight,
+            "tags" => &mut columns.tags,
+            _ => {
+                return Err(anyhow!(
+                    "unknown option `{key}` when including `{}`, \
+                    expect `column`, `weight` or `tags`",
+                    path.display()
+                ))
+            }
+        };
+        *slot = Some(value);
+    }
+
+    match format {
+        TableFormat::Csv => read_sheet(content, b',', &columns),
+        TableFormat::Tsv => read_sheet(content, b'\t', &columns),
+        TableFormat::Json => read_json(content, &columns),
+    }
+    .map_err(|err| anyhow!("cannot include `{}`: {err}", path.display()))
+}
+
+fn read_sheet(content: &str, delimiter: u8, columns: &Columns) -> Result<ExprSectionBody> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .quoting(delimiter != b'\t')
+        .flexible(true)
+        .from_reader(content.as_bytes());
+
+    let headers = reader.headers()?.clone();
+    let find = |name: Option<&str>| -> Result<Option<usize>> {
+        name.map(|name| {
+            headers
+                .iter()
+                .position(|h| h.trim() == name)
+                .ok_or_else(|| anyhow!("column `{name}` is not found in the header"))
+        })
+        .transpose()
+    };
+    let word_col = find(columns.word)?.unwrap_or(0);
+    let weight_col = find(columns.weight)?;
+    let tags_col = find(columns.tags)?;
+
+    let mut rules = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let cell = |col: usize| record.get(col).unwrap_or("").trim();
+
+        let word = cell(word_col);
+        if word.is_empty() {
+            continue;
+        }
+        let weight = match weight_col.map(cell) {
+            None | Some("") => 1,
+            Some(w) => parse_weight(w).map_err(|err| anyhow!("{err} at line {line}"))?,
+        };
+        let tags = tags_col.map(cell).map(split_tags).unwrap_or_default();
+        rules.push(word_rule(word, tags, weight));
+    }
+    Ok(rules)
+}
+
+fn read_json(content: &str, columns: &Columns) -> Result<ExprSectionBody> {
+    let Value::Array(items) = serde_json::from_str(content)? else {
+        return Err(anyhow!("expect an array of words or objects"));
+    };
+
+    let mut rules = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let (word, weight, tags) = match item {
+            Value::String(word) => (word.as_str(), 1, Vec::new()),
+            Value::Object(obj) => {
+                let key = columns
+                    .word
+                    .ok_or_else(|| anyhow!("objects need a `column` option to find their words"))?;
+                let word = obj
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("item {index} has no string field `{key}`"))?;
+
+                let weight = match columns.weight.and_then(|k| obj.get(k)) {
+                    None | Some(Value::Null) => 1,
+                    Some(Value::Number(n)) => parse_weight(&n.to_string())?,
+                    Some(Value::String(s)) => parse_weight(s)?,
+                    Some(_) => return Err(anyhow!("weight of item {index} is not a number")),
+                };
+
+                let tags = match columns.tags.and_then(|k| obj.get(k)) {
+                    None | Some(Value::Null) => Vec::new(),
+                    Some(Value::String(s)) => split_tags(s),
+                    Some(Value::Array(arr)) => arr.iter().filter_map(Value::as_str).collect(),
+                    Some(_) => return Err(anyhow!("tags of item {index} are not strings")),
+                };
+                (word, weight, tags)
+            }
+            _ => return Err(anyhow!("item {index} is neither a string nor an object")),
+        };
+
+        let word = word.trim();
+        if !word.is_empty() {
+            rules.push(word_rule(word, tags, weight));
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_weight(s: &str) -> Result<u32> {
+    match s.trim().parse() {
+        Ok(w) if w > 0 => Ok(w),
+        _ => Err(anyhow!("weight `{s}` is not a positive integer")),
+    }
+}
+
+fn split_tags(s: &str) -> Vec<&str> {
+    s.split(|ch: char| ch.is_whitespace() || ",，;；#".contains(ch))
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+fn word_rule(word: &str, tags: Vec<&str>, weight: u32) -> ExprRule {
+    ExprRule {
+        span: None,
+        segs: vec![ExprSeg::Quoted(ShareStr::new(word))],
+        tags: tags.into_iter().map(ShareStr::new).collect(),
+        attrs: Vec::new(),
+        weight,
+        normalized: false,
+    }
+}
+
+#[test]
+fn test_read_table() {
+    use super::{parse_tokens::parse, source::MemorySources};
+
+    let read = |header: &str, path: &str, content: &str| -> Result<Vec<(String, u32, String)>> {
+        let mut sources: MemorySources = [(path, content)].into_iter().collect();
+        sources.insert("lib/entry.txt", format!("{header}\n[entry]\n{{表}}\n"));
+        let (sections, _) = parse(&sources, Path::new("lib"))?;
+        let table = sections.iter().find(|sec| sec.info.name.as_str() == "表");
+        Ok(table
+            .unwrap()
+            .rules
+            .iter()
+            .map(|rule| {
+                let word = rule.segs[0].source_text().to_string();
+                let tags: Vec<&str> = rule.tags.iter().map(ShareStr::as_str).collect();
+                (word, rule.weight, tags.join(" "))
+            })
+            .collect())
+    };
+    let rule = |word: &str, weight: u32, tags: &str| (word.to_owned(), weight, tags.to_owned());
+
+    // 按表头找列，空的词跳过，空的权重当作1
+    let csv =
+        "编号,名称,权重,标签\n1,苹果,3,水果 甜\n2,\"饼,干\",,零食；点心\n3,,2,空\n4, 米饭 ,1,\n";
+    assert_eq!(
+        read(
+            "[include csv \"表.csv\" column=\"名称\" weight=\"权重\" tags=\"标签\" as 表]",
+            "lib/表.csv",
+            csv,
+        )
+        .unwrap(),
+        [
+            rule("苹果", 3, "水果 甜"),
+            rule("饼,干", 1, "零食 点心"),
+            rule("米饭", 1, ""),
+        ]
+    );
+    // 默认取第一列
+    assert_eq!(
+        read("[include csv \"表.csv\" as 表]", "lib/表.csv", csv).unwrap(),
+        [
+            rule("1", 1, ""),
+            rule("2", 1, ""),
+            rule("3", 1, ""),
+            rule("4", 1, "")
+        ]
+    );
+    // 制表符分隔的表里引号就是字
+    assert_eq!(
+        read(
+            "[include tsv \"表.tsv\" column=\"名称\" weight=\"权重\" as 表]",
+            "lib/表.tsv",
+            "名称\t权重\n面条\t2\n\"汤\t\n",
+        )
+        .unwrap(),
+        [rule("面条", 2, ""), rule("\"汤", 1, "")]
+    );
+
+    assert_eq!(
+        read(
+            "[include json \"表.json\" as 表]",
+            "lib/表.json",
+            "[\"苹果\", \" 香蕉 \", \"\"]",
+        )
+        .unwrap(),
+        [rule("苹果", 1, ""), rule("香蕉", 1, "")]
+    );
+    assert_eq!(
+        read(
+            "[include json \"表.json\" column=\"名\" weight=\"重\" tags=\"签\" as 表]",
+            "lib/表.json",
+            "[{\"名\": \"苹果\", \"重\": 2, \"签\": [\"水果\", \"甜\"]},\
+            {\"名\": \"饼\", \"重\": \"3\", \"签\": \"零食, 点心\"}, {\"名\": \"茶\", \"重\": null}]",
+        )
+        .unwrap(),
+        [
+            rule("苹果", 2, "水果 甜"),
+            rule("饼", 3, "零食 点心"),
+            rule("茶", 1, ""),
+        ]
+    );
+
+    let err = read(
+        "[include csv \"表.csv\" column=\"没有\" as 表]",
+        "lib/表.csv",
+        csv,
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("cannot include `lib/表.csv`: column `没有` is not found in the header"),
+        "{err}"
+    );
+    let err = read(
+        "[include csv \"表.csv\" weight=\"权重\" as 表]",
+        "lib/表.csv",
+        "名称,权重\n苹果,2\n饼,很多\n",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("weight `很多` is not a positive integer at line 3"),
+        "{err}"
+    );
+    let err = read(
+        "[include json \"表.json\" weight=\"重\" column=\"名\" as 表]",
+        "lib/表.json",
+        "[{\"名\": \"苹果\", \"重\": 0}]",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("weight `0` is not a positive integer"),
+        "{err}"
+    );
+    let err = read(
+        "[include json \"表.json\" as 表]",
+        "lib/表.json",
+        "[\"苹果\", ",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("cannot include `lib/表.json`: EOF"),
+        "{err}"
+    );
+    let err = read(
+        "[include json \"表.json\" as 表]",
+        "lib/表.json",
+        "[{\"名\": \"苹果\"}]",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("objects need a `column` option"),
+        "{err}"
+    );
+    let err = read(
+        "[include csv \"表.csv\" sheet=\"一\" as 表]",
+        "lib/表.csv",
+        csv,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("unknown option `sheet`"), "{err}");
+}
@@ -0,0 +1,371 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+
+use crate::{
+    share_str::ShareStr,
+    syntax::{Seg, SerializeMap},
+};
+
+use super::parse_tokens::{ExprRef, ExprRule, ExprSeg};
+
+/// Read a Tracery grammar such as `{"origin": ["#adj# #noun#"]}`, one section per symbol.
+/// Actions that save a symbol as `[x:#sym#]` become captures, other actions and modifiers
+/// cannot be represented, they are dropped with a note each. A variable read in another
+/// rule than the one saving it is read as a new word of the saved symbol, with a note.
+pub fn import(
+    path: &Path,
+    content: &str,
+    notes: &mut Vec<String>,
+) -> Result<Vec<(String, Vec<ExprRule>)>> {
+    let Value::Object(grammar) = serde_json::from_str(content)
+        .map_err(|err| anyhow!("cannot read Tracery grammar `{}`: {err}", path.display()))?
+    else {
+        return Err(anyhow!(
+            "Tracery grammar `{}` must be an object of symbols",
+            path.display()
+        ));
+    };
+
+    let mut symbols = Vec::new();
+    // 变量名 -> 存进去的符号，来自整个语法
+    let mut saved_anywhere: HashMap<String, String> = HashMap::new();
+    for (symbol, value) in &grammar {
+        let texts = match value {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(arr) => arr
+                .iter()
+                .map(|v| {
+                    v.as_str().ok_or_else(|| {
+                        anyhow!("rules of Tracery symbol `{symbol}` must be strings")
+                    })
+                })
+                .collect::<Result<_>>()?,
+            _ => {
+                return Err(anyhow!(
+                    "Tracery symbol `{symbol}` must be a string or an array of strings"
+                ))
+            }
+        };
+
+        let mut rules = Vec::new();
+        for text in texts {
+            let mut note = |what: String| {
+                notes.push(format!(
+                    "{what} in rule `{text}` of Tracery symbol `{symbol}` \
+                    in file `{}` is not supported and ignored",
+                    path.display()
+                ))
+            };
+
+            let (segs, saved) = import_rule(text, &mut note);
+            if segs.is_empty() {
+                note("empty rule".into());
+                continue;
+            }
+            for (var, saved) in saved {
+                saved_anywhere
+                    .entry(var.into())
+                    .or_insert_with(|| saved.into());
+            }
+            rules.push(ExprRule {
+                span: None,
+                segs,
+                tags: Vec::new(),
+                attrs: Vec::new(),
+                weight: 1,
+                normalized: false,
+            });
+        }
+        symbols.push((symbol.clone(), rules));
+    }
+
+    // 变量只在存它的那条规则里才是同一个词，别处只能当成重新展开的符号
+    for (symbol, rules) in &mut symbols {
+        for seg in rules.iter_mut().flat_map(|rule| &mut rule.segs) {
+            let ExprSeg::Use {
+                target,
+                capture: None,
+            } = seg
+            else {
+                continue;
+            };
+            if grammar.contains_key(target.name.as_str()) {
+                continue;
+            }
+            if let Some(saved) = saved_anywhere.get(target.name.as_str()) {
+                notes.push(format!(
+                    "variable `{}` read by Tracery symbol `{symbol}` in file `{}` \
+                    is saved in another rule, it is read as a new `{saved}` instead",
+                    target.name,
+                    path.display()
+                ));
+                target.name = ShareStr::new(saved);
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// Segments of a rule and the variables its actions save, with the symbols saved.
+fn import_rule<'a>(
+    text: &'a str,
+    note: &mut impl FnMut(String),
+) -> (Vec<ExprSeg>, Vec<(&'a str, &'a str)>) {
+    let mut segs = Vec::new();
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    // 变量名 -> (符号, 是否已经展开过)
+    let mut vars: HashMap<&str, (&str, bool)> = HashMap::new();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => plain.extend(chars.next()),
+            '#' => {
+                let rest = chars.as_str();
+                let Some(end) = rest.find('#') else {
+                    plain.push('#');
+                    continue;
+                };
+                let (symbol, modifiers) = rest[..end].split_once('.').unwrap_or((&rest[..end], ""));
+                if !modifiers.is_empty() {
+                    note(format!("modifier `.{modifiers}`"));
+                }
+                if !plain.is_empty() {
+                    segs.push(ExprSeg::Text(ShareStr::new(&std::mem::take(&mut plain))));
+                }
+                segs.push(match vars.get_mut(symbol) {
+                    Some((_, true)) => ExprSeg::Recall {
+                        capture: ShareStr::new(symbol),
+                        attr: None,
+                    },
+                    Some((saved, used)) => {
+                        *used = true;
+                        ExprSeg::Use {
+                            target: ExprRef {
+                                name: ShareStr::new(saved),
+                                args: Vec::new(),
+                                filter: Vec::new(),
+                            },
+                            capture: Some(ShareStr::new(symbol)),
+                        }
+                    }
+                    None => ExprSeg::Use {
+                        target: ExprRef {
+                            name: ShareStr::new(symbol),
+                            args: Vec::new(),
+                            filter: Vec::new(),
+                        },
+                        capture: None,
+                    },
+                });
+                chars = rest[end + 1..].chars();
+            }
+            '[' => {
+                // 动作可以嵌套，跳过配对的方括号
+                let rest = chars.as_str();
+                let mut depth = 1;
+                let end = rest.find(|ch| {
+                    match ch {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                });
+                let end = end.unwrap_or(rest.len());
+                let action = &rest[..end];
+                let saved = action.split_once(':').and_then(|(var, value)| {
+                    let symbol = value.strip_prefix('#')?.strip_suffix('#')?;
+                    let plain = |s: &str| !s.is_empty() && !s.contains(['#', '.', '[', ']']);
+                    (plain(var) && plain(symbol)).then_some((var, symbol))
+                });
+                match saved {
+                    Some((var, symbol)) => {
+                        vars.insert(var, (symbol, false));
+                    }
+                    None => note(format!("action `[{action}]`")),
+                }
+                chars = rest[(end + 1).min(rest.len())..].chars();
+            }
+            _ => plain.push(ch),
+        }
+    }
+
+    if !plain.is_empty() {
+        segs.push(ExprSeg::Text(ShareStr::new(&plain)));
+    }
+    let saved = vars.into_iter().map(|(var, (symbol, _))| (var, symbol));
+    (segs, saved.collect())
+}
+
+/// Write a compiled library as a Tracery grammar. The first section becomes `origin`
+/// and section `i` becomes `s{i}`, repeated words become actions. Tracery has no weights
+/// or case folding, sections that have them are written without, with a note each.
+pub fn export(map: &SerializeMap, notes: &mut Vec<String>) -> Result<String> {
+    let symbol = |i: u32| match i {
+        0 => "origin".to_owned(),
+        _ => format!("s{i}"),
+    };
+
+    let mut grammar = Map::new();
+    for (i, sec) in (0u32..).zip(map) {
+        if !sec.weights.is_empty() {
+            notes.push(format!(
+                "weights of section `{}` are dropped, Tracery picks every rule equally",
+                symbol(i)
+            ));
+        }
+        if sec.ignore_case {
+            notes.push(format!(
+                "section `{}` ignores case, but Tracery text is only read as written",
+                symbol(i)
+            ));
+        }
+
+        let mut rules = Vec::new();
+        for (j, rule) in sec.encoder.iter().enumerate() {
+            let recalled: HashSet<u32> = rule
+                .iter()
+                .filter_map(|seg| match seg {
+                    &Seg::Recall(r) => Some(r),
+                    _ => None,
+                })
+                .collect();
+            let var = |r: u32| format!("_s{i}_{j}_{r}");
+
+            let mut text = String::new();
+            for (seg, r) in rule.iter().zip(0u32..) {
+                match seg {
+                    Seg::Text(t) => {
+                        for ch in t.chars() {
+                            if "#[]\\".contains(ch) {
+                                text.push('\\');
+                            }
+                            text.push(ch);
+                        }
+                    }
+                    &Seg::Use(u) if recalled.contains(&r) => {
+                        write!(text, "[{0}:#{1}#]#{0}#", var(r), symbol(u)).unwrap()
+                    }
+                    &Seg::Use(u) => write!(text, "#{}#", symbol(u)).unwrap(),
+                    &Seg::Recall(r) => write!(text, "#{}#", var(r)).unwrap(),
+                    Seg::Generate(name) => {
+                        return Err(anyhow!("generator `{name}` cannot be exported to Tracery"))
+                    }
+                    Seg::Attr { .. } => {
+                        return Err(anyhow!("attributes of words cannot be exported to Tracery"))
+                    }
+                }
+            }
+            rules.push(Value::String(text));
+        }
+        grammar.insert(symbol(i), Value::Array(rules));
+    }
+
+    Ok(serde_json::to_string_pretty(&Value::Object(grammar))?)
+}
+
+#[test]
+fn test_import_rule() {
+    let mut notes = Vec::new();
+    let (segs, saved) = import_rule(
+        "[hero:#name#][pop]#hero.capitalize# \\#1 #adj##hero#",
+        &mut |n| notes.push(n),
+    );
+    assert_eq!(saved, [("hero", "name")]);
+
+    let shape: Vec<String> = segs
+        .iter()
+        .map(|seg| match seg {
+            ExprSeg::Text(t) => t.to_string(),
+            ExprSeg::Use {
+                target,
+                capture: Some(c),
+            } => format!("{{{target}:{c}}}"),
+            ExprSeg::Use { target, .. } => format!("{{{target}}}"),
+            ExprSeg::Recall { capture, .. } => format!("{{{capture}}}"),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(shape, ["{name:hero}", " #1 ", "{adj}", "{hero}"]);
+    assert_eq!(notes.len(), 2);
+}
+
+#[test]
+fn test_export() {
+    use super::{compile_from, CompileOptions, MemorySources};
+
+    let sources: MemorySources = [
+        (
+            "lib/entry.txt",
+            "[include csv \"食物.csv\" column=\"词\" weight=\"权重\" as 食物]\n\
+            [entry]\n{食物:吃的}配{名字}，还是{$吃的}\n[!ignore-case]\n[名字]\nAnn\nBob\n",
+        ),
+        ("lib/食物.csv", "词,权重\n饭,3\n面,1\n"),
+    ]
+    .into_iter()
+    .collect();
+    let compiled = compile_from(&sources, "lib", &CompileOptions::default()).unwrap();
+    let mut notes = Vec::new();
+    let grammar = export(&compiled.map, &mut notes).unwrap();
+    assert_eq!(notes.len(), 2, "{notes:?}");
+    assert!(notes.iter().any(|n| n.starts_with("weights of section")));
+    assert!(notes.iter().any(|n| n.contains("ignores case")));
+    assert_eq!(
+        serde_json::from_str::<Value>(&grammar).unwrap(),
+        serde_json::json!({
+            "origin": ["[_s0_0_0:#s1#]#_s0_0_0#配#s2#，还是#_s0_0_0#"],
+            "s1": ["饭", "面"],
+            "s2": ["Ann", "Bob"],
+        })
+    );
+
+    // 导出的语法再导入，重复的词还是同一个
+    let sources: MemorySources = [
+        (
+            "lib/entry.txt",
+            "[include tracery \"语法.json\"]\n[entry]\n{origin}\n",
+        ),
+        ("lib/语法.json", &grammar),
+    ]
+    .into_iter()
+    .collect();
+    let compiled = compile_from(&sources, "lib", &CompileOptions::default()).unwrap();
+    let text = crate::encode(&compiled.map, b"tracery").unwrap();
+    assert_eq!(crate::decode(&compiled.map, &text).unwrap(), b"tracery");
+    // 同一个词每次都出现两遍
+    for sentence in text
+        .split_inclusive(['饭', '面'])
+        .collect::<Vec<_>>()
+        .chunks(2)
+    {
+        let [first, second] = sentence else { break };
+        assert_eq!(first.chars().next(), second.chars().last(), "{text}");
+    }
+}
+
+#[test]
+fn test_variable_in_other_rule() {
+    let mut notes = Vec::new();
+    let symbols = import(
+        Path::new("语法.json"),
+        r##"{"origin": ["[hero:#name#]#hero#和#friend#"], "friend": ["#hero#的朋友"], "name": ["甲", "乙"]}"##,
+        &mut notes,
+    )
+    .unwrap();
+
+    let (_, friend) = symbols.iter().find(|(s, _)| s == "friend").unwrap();
+    let ExprSeg::Use { target, capture } = &friend[0].segs[0] else {
+        panic!()
+    };
+    assert_eq!((target.name.as_str(), capture), ("name", &None));
+    assert_eq!(notes.len(), 1);
+    assert!(notes[0].contains("variable `hero`"), "{notes:?}");
+}
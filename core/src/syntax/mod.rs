@@ -1,7 +1,11 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 #[cfg(feature = "compile")]
-pub use compiler::compile;
+pub use compiler::{
+    compile, compile_from, compile_with, compile_with_notes, export_tracery, lint, lint_from,
+    CompileOptions, Compiled, Diagnostic, Diagnostics, FileSystem, LibraryBuilder, Limits,
+    LintOptions, Location, MemorySources, RulePart, SourceProvider,
+};
 
 use crate::share_str::ShareStr;
 
@@ -14,16 +18,69 @@ pub type SerializeMap = Vec<Section>;
 pub struct Section {
     pub encoder: Vec<Vec<Seg>>,
     pub decoder: Layer,
+    /// Text of the rules is matched regardless of case, the keys of `decoder` it is read
+    /// by are folded by [`fold_case`] and marked [`Layer::Folded`].
+    pub ignore_case: bool,
+    /// Tags of each rule kept for tools, empty if no rule has any.
+    pub tags: Vec<Vec<ShareStr>>,
+    /// Weight of each rule, empty if every rule weighs 1.
+    pub weights: Vec<u32>,
+}
+
+impl Section {
+    /// Where to split rules `start..end` into two choices of one bit. The halves have
+    /// about equal weights, so a heavier rule takes fewer bits and is chosen more often.
+    pub fn split(&self, start: usize, end: usize) -> usize {
+        if self.weights.is_empty() {
+            return (start + end) / 2;
+        }
+
+        let weights = &self.weights[start..end];
+        let total: u64 = weights.iter().map(|&w| w as u64).sum();
+        let mut left = 0;
+        let mut best = (u64::MAX, start + 1);
+        for (i, &w) in weights[..weights.len() - 1].iter().enumerate() {
+            left += w as u64;
+            // 相同时取靠前的，所有权重为1时和不带权重的结果一样
+            let diff = left.abs_diff(total - left);
+            if diff < best.0 {
+                best = (diff, start + i + 1);
+            }
+        }
+        best.1
+    }
 }
 
 #[derive(Debug)]
 pub enum Seg {
     Text(ShareStr),
     Use(u32),
+    Generate(ShareStr),
+    Recall(u32),
+    Attr { seg: u32, values: Vec<ShareStr> },
 }
 
 #[derive(Debug)]
 pub enum Layer {
-    Branch(HashMap<char, Layer>),
+    Branch(BTreeMap<char, Layer>),
     Certain(u32),
+    /// Read a whole text of the section at this index, then go on with `next`.
+    Through {
+        section: u32,
+        next: Box<Layer>,
+    },
+    /// The rules cannot be told apart by looking ahead, so the decoder tries each of them.
+    Search,
+    /// Reached from a [`Layer::Branch`] by a key folded by [`fold_case`], which matches
+    /// an input character of any case.
+    Folded(Box<Layer>),
+}
+
+/// Lower case of a character, if it is a single character.
+pub fn fold_case(ch: char) -> char {
+    let mut lower = ch.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => ch,
+    }
 }
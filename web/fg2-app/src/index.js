@@ -20,9 +20,13 @@ function setPasteBtnText() {
 setPasteBtnText();
 
 encBtn.onclick = () => {
-    let out = GLOBAL_LIB.encode(inputArea.value);
-    outputArea.style.color = "";
-    outputArea.value = out;
+    try {
+        outputArea.value = GLOBAL_LIB.encode(inputArea.value);
+        outputArea.style.color = "";
+    } catch (err) {
+        outputArea.value = err;
+        outputArea.style.color = "orangered";
+    }
 };
 
 decBtn.onclick = () => {
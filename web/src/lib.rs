@@ -2,7 +2,7 @@ mod utils;
 
 use std::fmt::Display;
 
-use food_generator2::syntax::SerializeMap;
+use food_generator2::file;
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -18,7 +18,7 @@ extern "C" {
 
 #[wasm_bindgen]
 pub struct Library {
-    lib: SerializeMap,
+    lib: file::Library,
 }
 
 #[wasm_bindgen]
@@ -28,18 +28,17 @@ impl Library {
             return Err("read no data".into());
         }
 
-        let lib = food_generator2::file::read_lib(&data)
-            .ok_or_else(|| JsValue::from_str("parse library failed"))?;
+        let lib = file::read_library(data)
+            .map_err(|err| JsValue::from_str(&format!("parse library failed: {err}")))?;
         Ok(Library { lib })
     }
 
-    pub fn encode(&self, txt: &str) -> String {
-        food_generator2::encode(&self.lib, txt.as_bytes())
+    pub fn encode(&self, txt: &str) -> Result<String, String> {
+        food_generator2::encode_library(&self.lib, txt).map_err(map_err)
     }
 
     pub fn decode(&self, txt: &str) -> Result<String, String> {
-        let bytes = food_generator2::decode(&self.lib, txt).map_err(map_err)?;
-        String::from_utf8(bytes).map_err(map_err)
+        food_generator2::decode_library(&self.lib, txt).map_err(map_err)
     }
 }
 